@@ -0,0 +1,108 @@
+use ethers::types::{Address, Log, U256};
+
+use crate::transfer::{address, signature_topic, words, Reject};
+
+/// Allowance set by an ERC20 owner. ERC721 approvals have the same signature
+/// but name a single token id as their last, indexed, parameter.
+pub const APPROVAL: &str = "Approval(address,address,uint256)";
+
+/// ERC20 allowance granted by `owner` to `spender`, replacing the previous one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Approval {
+    pub token: Address,
+    pub owner: Address,
+    pub spender: Address,
+    pub value: U256,
+}
+
+impl Approval {
+    /// Decodes an ERC20 `Approval` log. Owners are matched on the first topic,
+    /// so it must be indexed as the standard says. ERC721 approvals, indexing
+    /// the token id as well, are not watched and give None.
+    pub fn decode(log: &Log) -> Result<Option<Approval>, Reject> {
+        if log.topics.first() != Some(&signature_topic(APPROVAL)) {
+            return Err(Reject::UnknownEvent);
+        }
+        if log.topics.len() == 4 && log.data.is_empty() {
+            return Ok(None);
+        }
+        if log.topics.len() != 3 {
+            return Err(Reject::Topics);
+        }
+        let words = words(log, 3)?;
+        Ok(Some(Approval {
+            token: log.address,
+            owner: address(words[0])?,
+            spender: address(words[1])?,
+            value: U256::from_big_endian(words[2].as_bytes()),
+        }))
+    }
+
+    /// `type(uint256).max`, the usual way of letting the spender move every
+    /// present and future token of the owner.
+    pub fn is_unlimited(&self) -> bool {
+        self.value == U256::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ethers::types::H256;
+
+    fn log(topics: &[H256], value: U256) -> Log {
+        let mut data = [0; 32];
+        value.to_big_endian(&mut data);
+        Log {
+            address: Address::from_low_u64_be(1),
+            topics: [&[signature_topic(APPROVAL)], topics].concat(),
+            data: data.to_vec().into(),
+            ..Default::default()
+        }
+    }
+
+    fn topic(address: u64) -> H256 {
+        H256::from(Address::from_low_u64_be(address))
+    }
+
+    #[test]
+    fn test_decode_approval() {
+        let approval = Approval::decode(&log(&[topic(2), topic(3)], U256::from(500)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            approval,
+            Approval {
+                token: Address::from_low_u64_be(1),
+                owner: Address::from_low_u64_be(2),
+                spender: Address::from_low_u64_be(3),
+                value: U256::from(500),
+            }
+        );
+        assert!(!approval.is_unlimited());
+        assert!(Approval::decode(&log(&[topic(2), topic(3)], U256::MAX))
+            .unwrap()
+            .unwrap()
+            .is_unlimited());
+    }
+
+    #[test]
+    fn test_decode_approval_rejects() {
+        let erc721 = Log {
+            data: Default::default(),
+            ..log(&[topic(2), topic(3), topic(4)], U256::zero())
+        };
+        assert_eq!(Approval::decode(&erc721), Ok(None));
+        let erc721_with_data = log(&[topic(2), topic(3), topic(4)], U256::zero());
+        assert_eq!(Approval::decode(&erc721_with_data), Err(Reject::Topics));
+        let unindexed = log(&[topic(2)], U256::zero());
+        assert_eq!(Approval::decode(&unindexed), Err(Reject::Topics));
+        let mut dirty = log(&[topic(2), topic(3)], U256::zero());
+        dirty.topics[2].0[0] = 1;
+        assert_eq!(Approval::decode(&dirty), Err(Reject::Address));
+        let mut transfer = log(&[topic(2), topic(3)], U256::zero());
+        transfer.topics[0] = H256::zero();
+        assert_eq!(Approval::decode(&transfer), Err(Reject::UnknownEvent));
+    }
+}
@@ -1,13 +1,16 @@
 use ethers::{
-    prelude::abigen,
     providers::{Provider, Ws},
-    types::Address,
+    types::{Address, U256},
 };
-use eyre::{eyre, Result};
+use eyre::Result;
+use serde::{Deserialize, Serialize};
 use std::{str::FromStr, sync::Arc};
 use teloxide::{
     dispatching::{
-        dialogue::{self, InMemStorage},
+        dialogue::{
+            self, serializer::Json, ErasedStorage, InMemStorage, RedisStorage, SqliteStorage,
+            Storage,
+        },
         UpdateHandler,
     },
     prelude::*,
@@ -16,45 +19,145 @@ use teloxide::{
 };
 use tokio::sync::RwLock;
 
-use crate::state::{State, AVAILABLE_CHAINS, CHAINS_INFO};
+use crate::{
+    config::Chains,
+    custom_event::CustomEvent,
+    filter::TransferFilter,
+    notifier::Notifier,
+    render,
+    state::{Direction, Saved, State, SubKind, Subscription},
+    token,
+    transfer::{RejectedLogs, NATIVE, NATIVE_DECIMALS},
+};
 
-type MyDialogue = Dialogue<ChatState, InMemStorage<ChatState>>;
+type MyDialogue = Dialogue<ChatState, ErasedStorage<ChatState>>;
 type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
-#[derive(Clone, Default)]
-enum ChatState {
+/// Persisted as JSON by the dialogue storage. Variants keep their names and
+/// fields added later have a default, dialogues stored by older versions must
+/// still load.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChatState {
     #[default]
     Start,
     ReceiveChainId,
     ReceiveTokenAddress {
         chain_id: u32,
     },
+    /// A `None` token matches transfers of any token.
     ReceiveUser {
+        chain_id: u32,
+        token_address: Option<Address>,
+    },
+    ReceiveDirection {
+        chain_id: u32,
+        token_address: Option<Address>,
+        user_address: Address,
+        label: Option<String>,
+    },
+    /// A `None` user address matches every transfer of the token.
+    ReceiveThreshold {
         chain_id: u32,
         token_address: Address,
+        user_address: Option<Address>,
+        label: Option<String>,
+        #[serde(default)]
+        direction: Direction,
+    },
+    ReceiveFilter {
+        chain_id: u32,
+        token_address: Option<Address>,
+        user_address: Option<Address>,
+        label: Option<String>,
+        min_amount: Option<U256>,
+        direction: Direction,
+    },
+    ReceiveEventChainId,
+    ReceiveEventContract {
+        chain_id: u32,
+    },
+    ReceiveEventAbi {
+        chain_id: u32,
+        contract_address: Address,
+        label: Option<String>,
     },
 }
 
 #[derive(BotCommands, Clone, Debug)]
 #[command(description = "Commands:", rename_rule = "lowercase")]
 enum Command {
+    #[command(description = "Start receiving notifications, again if the bot was blocked")]
+    Start,
     #[command(description = "Display all commands")]
     Help,
     #[command(description = "Subscribe to receive notifications of token transfers")]
     Subscribe,
+    #[command(description = "Subscribe to any event of a contract, given its signature or ABI")]
+    Event,
     #[command(
         description = "Unsubscribe of token transfer, by passing in the id. Ids can be obtained in the /subs command"
     )]
-    Unsubscribe(u32),
+    Unsubscribe(u64),
     #[command(description = "Display all current token subscriptions")]
     Subs,
     #[command(description = "Cancel susbscription process")]
     Cancel,
+    #[command(description = "off")]
+    Status,
+}
+
+/// Callback data of the "Remove" buttons, followed by the subscription id.
+const REMOVE_PREFIX: &str = "unsub:";
+/// Callback data of the /subs navigation buttons, followed by the page index.
+const SUBS_PAGE_PREFIX: &str = "subs:";
+const SUBS_PER_PAGE: usize = 10;
+const MAX_LABEL_LEN: usize = 64;
+
+/// Sent instead of a token or user address to be notified about all of them.
+const ANY: &str = "any";
+/// Keyword standing for the native currency of a chain when picking a token.
+const NATIVE_TOKEN: &str = "native";
+/// Sent instead of a filter expression to be notified about every transfer.
+const NO_FILTER: &str = "none";
+
+/// Chat allowed to use the operator commands, if any.
+#[derive(Clone, Copy, Debug)]
+pub struct Operator(pub Option<ChatId>);
+
+/// Where dialogue states are kept between updates.
+pub enum DialogueStorage {
+    /// Lost on restart, users in the middle of /subscribe start over.
+    Memory,
+    /// Path of the SQLite database file.
+    Sqlite(String),
+    /// Redis connection url.
+    Redis(String),
+}
+
+impl DialogueStorage {
+    pub async fn open(self) -> Result<Arc<ErasedStorage<ChatState>>> {
+        let storage = match self {
+            DialogueStorage::Memory => InMemStorage::<ChatState>::new().erase(),
+            DialogueStorage::Sqlite(path) => SqliteStorage::open(&path, Json).await?.erase(),
+            DialogueStorage::Redis(url) => RedisStorage::open(url, Json).await?.erase(),
+        };
+        Ok(storage)
+    }
 }
 
-pub async fn run(bot: Bot, state: Arc<RwLock<State>>) {
+pub async fn run(
+    bot: Bot,
+    state: Arc<RwLock<State>>,
+    chains: Arc<Chains>,
+    notifier: Notifier,
+    rejected: RejectedLogs,
+    operator: Operator,
+    storage: Arc<ErasedStorage<ChatState>>,
+) {
     Dispatcher::builder(bot, schema())
-        .dependencies(dptree::deps![state, InMemStorage::<ChatState>::new()])
+        .dependencies(dptree::deps![
+            state, chains, notifier, rejected, operator, storage
+        ])
         .error_handler(LoggingErrorHandler::with_custom_text(
             "An error has occurred in the dispatcher",
         ))
@@ -72,9 +175,12 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
             case![ChatState::Start]
                 .branch(case![Command::Help].endpoint(help))
                 .branch(case![Command::Subscribe].endpoint(subscribe))
+                .branch(case![Command::Event].endpoint(subscribe_event))
                 .branch(case![Command::Unsubscribe(id)].endpoint(unsubscribe))
-                .branch(case![Command::Subs].endpoint(subs)),
+                .branch(case![Command::Subs].endpoint(subs))
+                .branch(case![Command::Status].endpoint(status)),
         )
+        .branch(case![Command::Start].endpoint(start))
         .branch(case![Command::Cancel].endpoint(cancel));
 
     let message_handler = Update::filter_message()
@@ -87,12 +193,69 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
             }]
             .endpoint(receive_user),
         )
+        .branch(
+            case![ChatState::ReceiveThreshold {
+                chain_id,
+                token_address,
+                user_address,
+                label,
+                direction
+            }]
+            .endpoint(receive_threshold),
+        )
+        .branch(
+            case![ChatState::ReceiveFilter {
+                chain_id,
+                token_address,
+                user_address,
+                label,
+                min_amount,
+                direction
+            }]
+            .endpoint(receive_filter),
+        )
+        .branch(
+            case![ChatState::ReceiveEventContract { chain_id }].endpoint(receive_event_contract),
+        )
+        .branch(
+            case![ChatState::ReceiveEventAbi {
+                chain_id,
+                contract_address,
+                label
+            }]
+            .endpoint(receive_event_abi),
+        )
         .branch(dptree::endpoint(invalid_state));
 
     let callback_query_handler = Update::filter_callback_query()
-        .branch(case![ChatState::ReceiveChainId].endpoint(receive_chain_id));
+        .branch(
+            dptree::filter_map(|q: CallbackQuery| {
+                q.data?.strip_prefix(REMOVE_PREFIX)?.parse::<u64>().ok()
+            })
+            .endpoint(remove_button),
+        )
+        .branch(
+            dptree::filter_map(|q: CallbackQuery| {
+                q.data?
+                    .strip_prefix(SUBS_PAGE_PREFIX)?
+                    .parse::<usize>()
+                    .ok()
+            })
+            .endpoint(subs_page_button),
+        )
+        .branch(case![ChatState::ReceiveChainId].endpoint(receive_chain_id))
+        .branch(case![ChatState::ReceiveEventChainId].endpoint(receive_event_chain_id))
+        .branch(
+            case![ChatState::ReceiveDirection {
+                chain_id,
+                token_address,
+                user_address,
+                label
+            }]
+            .endpoint(receive_direction),
+        );
 
-    dialogue::enter::<Update, InMemStorage<ChatState>, ChatState, _>()
+    dialogue::enter::<Update, ErasedStorage<ChatState>, ChatState, _>()
         .branch(message_handler)
         .branch(callback_query_handler)
 }
@@ -113,25 +276,67 @@ async fn cancel(bot: Bot, msg: Message, dialogue: MyDialogue) -> HandlerResult {
     Ok(())
 }
 
+/// Sent by Telegram when a user opens or unblocks the bot, resumes their subscriptions.
+async fn start(bot: Bot, msg: Message, state: Arc<RwLock<State>>) -> HandlerResult {
+    let reactivated = state
+        .write()
+        .await
+        .set_chat_active(msg.chat.id, true)
+        .map_err(|e| e.to_string())?;
+    if reactivated {
+        bot.send_message(
+            msg.chat.id,
+            "Welcome back! Your subscriptions are active again.",
+        )
+        .await?;
+    } else {
+        help(bot, msg).await?;
+    }
+    Ok(())
+}
+
 async fn help(bot: Bot, msg: Message) -> HandlerResult {
     bot.send_message(msg.chat.id, Command::descriptions().to_string())
         .await?;
     Ok(())
 }
 
-async fn subscribe(bot: Bot, msg: Message, dialogue: MyDialogue) -> HandlerResult {
+async fn subscribe(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    chains: Arc<Chains>,
+) -> HandlerResult {
     bot.send_message(msg.chat.id, "Let's start! Select desired chain.")
         .await?;
-    let chains = AVAILABLE_CHAINS
-        .keys()
-        .map(|chain| InlineKeyboardButton::callback(chain.to_string(), chain.to_string()));
     bot.send_message(msg.chat.id, "Select a chain:")
-        .reply_markup(InlineKeyboardMarkup::new([chains]))
+        .reply_markup(chain_keyboard(&chains))
         .await?;
     dialogue.update(ChatState::ReceiveChainId).await?;
     Ok(())
 }
 
+async fn subscribe_event(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    chains: Arc<Chains>,
+) -> HandlerResult {
+    bot.send_message(msg.chat.id, "Select the chain of the contract:")
+        .reply_markup(chain_keyboard(&chains))
+        .await?;
+    dialogue.update(ChatState::ReceiveEventChainId).await?;
+    Ok(())
+}
+
+/// One button per enabled chain, answered with the chain id.
+fn chain_keyboard(chains: &Chains) -> InlineKeyboardMarkup {
+    let chains = chains
+        .enabled()
+        .map(|chain| InlineKeyboardButton::callback(chain.name.clone(), chain.id.to_string()));
+    InlineKeyboardMarkup::new([chains])
+}
+
 async fn unsubscribe(
     bot: Bot,
     msg: Message,
@@ -139,55 +344,206 @@ async fn unsubscribe(
     state: Arc<RwLock<State>>,
 ) -> HandlerResult {
     match cmd {
-        Command::Unsubscribe(index) => {
-            let mut state = state.write().await;
-            if let Ok(subscription) = state.remove_sub(&msg.chat.id, index as usize) {
-                bot.send_message(
-                    msg.chat.id,
-                    format!("Succesfully unsubscribed from {:?}", subscription),
-                )
-                .await?;
-            } else {
-                bot.send_message(msg.chat.id, format!("Error invalid index."))
-                    .await?;
-            }
-        }
+        Command::Unsubscribe(id) => remove_subscription(&bot, msg.chat.id, &state, id).await,
         _ => {
             panic!("unexpected state");
         }
     }
+}
 
+async fn remove_button(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    state: Arc<RwLock<State>>,
+    id: u64,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+    remove_subscription(&bot, dialogue.chat_id(), &state, id).await
+}
+
+async fn remove_subscription(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &RwLock<State>,
+    id: u64,
+) -> HandlerResult {
+    let removed = state.write().await.remove_sub(&chat_id, id);
+    if let Ok(subscription) = removed {
+        bot.send_message(
+            chat_id,
+            format!("Succesfully unsubscribed from #{}.", subscription.id),
+        )
+        .await?;
+    } else {
+        bot.send_message(chat_id, "Error invalid subscription id.")
+            .await?;
+    }
+    Ok(())
+}
+
+async fn subs(
+    bot: Bot,
+    msg: Message,
+    state: Arc<RwLock<State>>,
+    chains: Arc<Chains>,
+) -> HandlerResult {
+    let page = subs_page(&*state.read().await, &chains, msg.chat.id, 0);
+    if let Some((text, keyboard)) = page {
+        bot.send_message(msg.chat.id, text)
+            .reply_markup(keyboard)
+            .await?;
+    } else {
+        bot.send_message(msg.chat.id, "You currently have no subs")
+            .await?;
+    }
     Ok(())
 }
 
-async fn subs(bot: Bot, msg: Message, state: Arc<RwLock<State>>) -> HandlerResult {
-    let state = state.read().await;
-    let subs = state.get_user_subscriptions_formated(&msg.chat.id);
-    if let Some(subs) = subs {
-        bot.send_message(msg.chat.id, format!("Your subs {:?}", subs))
+async fn subs_page_button(
+    bot: Bot,
+    q: CallbackQuery,
+    state: Arc<RwLock<State>>,
+    chains: Arc<Chains>,
+    page: usize,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+    let Some(message) = q.message else {
+        return Ok(());
+    };
+    let page = subs_page(&*state.read().await, &chains, message.chat.id, page);
+    if let Some((text, keyboard)) = page {
+        bot.edit_message_text(message.chat.id, message.id, text)
+            .reply_markup(keyboard)
             .await?;
     } else {
-        bot.send_message(msg.chat.id, format!("You currently have no subs"))
+        bot.edit_message_text(message.chat.id, message.id, "You currently have no subs")
             .await?;
     }
     Ok(())
 }
 
-async fn receive_chain_id(bot: Bot, dialogue: MyDialogue, q: CallbackQuery) -> HandlerResult {
-    if let Some(chain_name) = q.data {
-        let chain_id = AVAILABLE_CHAINS.get(chain_name.as_str()).unwrap();
+/// Text and buttons of a /subs page, None if the user has no subscriptions.
+fn subs_page(
+    state: &State,
+    chains: &Chains,
+    chat_id: ChatId,
+    page: usize,
+) -> Option<(String, InlineKeyboardMarkup)> {
+    let subs = state.get_user_subs(&chat_id);
+    let entries: Vec<String> = subs
+        .iter()
+        .map(|sub| {
+            let chain_name = chains
+                .get(&sub.chain_id)
+                .map_or("Unknown chain", |chain| chain.name.as_str());
+            let token = sub
+                .token_address
+                .and_then(|token_address| token_info(state, chains, sub.chain_id, token_address));
+            let token = token
+                .as_ref()
+                .map(|(symbol, decimals)| (symbol.as_str(), *decimals));
+            render::subscription(sub, chain_name, token)
+        })
+        .collect();
+    // Leaves room for the header.
+    let pages = render::paginate(&entries, SUBS_PER_PAGE, render::MAX_MESSAGE_LEN - 100);
+    let page = page.min(pages.len().checked_sub(1)?);
+    let range = pages[page].clone();
+
+    let text = format!(
+        "Your subscriptions, page {} of {}:\n\n{}",
+        page + 1,
+        pages.len(),
+        entries[range.clone()].join("\n\n")
+    );
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = subs[range]
+        .iter()
+        .map(|sub| {
+            vec![InlineKeyboardButton::callback(
+                format!("Remove #{}", sub.id),
+                format!("{}{}", REMOVE_PREFIX, sub.id),
+            )]
+        })
+        .collect();
+    let mut navigation = Vec::new();
+    if page > 0 {
+        navigation.push(InlineKeyboardButton::callback(
+            "« Prev",
+            format!("{}{}", SUBS_PAGE_PREFIX, page - 1),
+        ));
+    }
+    if page + 1 < pages.len() {
+        navigation.push(InlineKeyboardButton::callback(
+            "Next »",
+            format!("{}{}", SUBS_PAGE_PREFIX, page + 1),
+        ));
+    }
+    if !navigation.is_empty() {
+        rows.push(navigation);
+    }
+    Some((text, InlineKeyboardMarkup::new(rows)))
+}
+
+/// Delivery statistics and rejected logs, only answered in the operator chat.
+async fn status(
+    bot: Bot,
+    msg: Message,
+    chains: Arc<Chains>,
+    notifier: Notifier,
+    rejected: RejectedLogs,
+    operator: Operator,
+) -> HandlerResult {
+    if operator.0 != Some(msg.chat.id) {
+        return invalid_state(bot, msg).await;
+    }
+    let status = notifier.status();
+    let mut text = format!(
+        "Queued: {}\nSending: {}\nDelivered: {}\nFailed: {}",
+        status.queued, status.in_flight, status.delivered, status.failed
+    );
+    for (chat_id, error) in status.recent_failures {
+        text.push_str(&format!("\n{}: {}", chat_id, error));
+    }
+    let rejected = rejected.counts();
+    if !rejected.is_empty() {
+        text.push_str("\nRejected logs:");
+    }
+    for (chain_id, reject, count) in rejected {
+        let chain_name = chains
+            .get(&chain_id)
+            .map_or("Unknown chain", |chain| chain.name.as_str());
+        text.push_str(&format!("\n{}: {} {}", chain_name, count, reject));
+    }
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+async fn receive_chain_id(
+    bot: Bot,
+    dialogue: MyDialogue,
+    q: CallbackQuery,
+    chains: Arc<Chains>,
+) -> HandlerResult {
+    let chain = q
+        .data
+        .and_then(|chain_id| chain_id.parse::<u32>().ok())
+        .and_then(|chain_id| chains.get(&chain_id))
+        .filter(|chain| chain.enabled);
+    if let Some(chain) = chain {
         bot.send_message(
             dialogue.chat_id(),
             format!(
-                "You've selected {} chain.\n Please insert the token address.",
-                chain_name
+                "You've selected {} chain.\n Please insert the token address, \"{}\" for {} transfers, or \"{}\" to be notified about transfers of any token.",
+                chain.name, NATIVE_TOKEN, chain.native_symbol, ANY
             ),
         )
         .await?;
         dialogue
-            .update(ChatState::ReceiveTokenAddress {
-                chain_id: chain_id.to_owned(),
-            })
+            .update(ChatState::ReceiveTokenAddress { chain_id: chain.id })
+            .await?;
+    } else {
+        bot.send_message(dialogue.chat_id(), "Chain is no longer available.")
             .await?;
     }
     Ok(())
@@ -198,9 +554,44 @@ async fn receive_token_address(
     msg: Message,
     dialogue: MyDialogue,
     state: Arc<RwLock<State>>,
+    chains: Arc<Chains>,
     chain_id: u32, // Available from `ChatState::ReceiveChainID`.
 ) -> HandlerResult {
+    // The chain may have been removed from the configuration since the
+    // dialogue was stored.
+    let Some(chain) = chains.get(&chain_id) else {
+        bot.send_message(msg.chat.id, "Unknown chain, please /subscribe again.")
+            .await?;
+        dialogue.exit().await?;
+        return Ok(());
+    };
     match msg.text().map(ToOwned::to_owned) {
+        Some(token_address) if token_address.trim().eq_ignore_ascii_case(ANY) => {
+            bot.send_message(
+                msg.chat.id,
+                "Transfers of any token will be notified.\n Please insert the user address, optionally followed by a label.",
+            )
+            .await?;
+            dialogue
+                .update(ChatState::ReceiveUser {
+                    chain_id,
+                    token_address: None,
+                })
+                .await?;
+        }
+        Some(token_address) if is_native(&chains, chain_id, token_address.trim()) => {
+            let response = format!(
+                "Native {} transfers will be notified.\n Please insert the user address, optionally followed by a label, or \"{}\" to be notified about large transfers between any addresses.",
+                chain.native_symbol, ANY
+            );
+            bot.send_message(msg.chat.id, response).await?;
+            dialogue
+                .update(ChatState::ReceiveUser {
+                    chain_id,
+                    token_address: Some(NATIVE),
+                })
+                .await?;
+        }
         Some(token_address) => {
             if let Ok(token_address) = Address::from_str(&token_address) {
                 let state_read = state.read().await;
@@ -208,43 +599,44 @@ async fn receive_token_address(
                     state_read.get_token_metadata(&chain_id, &token_address)
                 {
                     let response = format!(
-                        "Target token has name: {}, and symbol: {} .\n Please insert the user address.",
-                        name, symbol
+                        "Target token has name: {}, and symbol: {} .\n Please insert the user address, optionally followed by a label, or \"{}\" to be notified about large transfers between any addresses.",
+                        name, symbol, ANY
                     );
                     bot.send_message(msg.chat.id, response).await?;
                     dialogue
                         .update(ChatState::ReceiveUser {
                             chain_id,
-                            token_address,
+                            token_address: Some(token_address),
                         })
                         .await?;
                 } else if let Ok((name, symbol, decimals)) =
-                    fetch_token_metadata(CHAINS_INFO.get(&chain_id).unwrap().ws, token_address)
-                        .await
+                    fetch_token_metadata(chain.rpc_url(), token_address).await
                 {
                     drop(state_read);
 
                     let mut state = state.write().await;
-                    state.insert_token_metadata(
-                        &chain_id,
-                        token_address,
-                        name.clone(),
-                        symbol.clone(),
-                        decimals,
-                    );
+                    state
+                        .insert_token_metadata(
+                            &chain_id,
+                            token_address,
+                            name.clone(),
+                            symbol.clone(),
+                            decimals,
+                        )
+                        .map_err(|e| e.to_string())?;
                     let response = format!(
-                        "Target token has name: {}, and symbol: {} .\n Please insert the user address.",
-                        name, symbol
+                        "Target token has name: {}, and symbol: {} .\n Please insert the user address, optionally followed by a label, or \"{}\" to be notified about large transfers between any addresses.",
+                        name, symbol, ANY
                     );
                     bot.send_message(msg.chat.id, response).await?;
                     dialogue
                         .update(ChatState::ReceiveUser {
                             chain_id,
-                            token_address,
+                            token_address: Some(token_address),
                         })
                         .await?;
                 } else {
-                    bot.send_message(msg.chat.id, "Address given does not correspond to a token, please insert an ERC20 token address.")
+                    bot.send_message(msg.chat.id, "Address given does not correspond to a token, please insert an ERC20, ERC721 or ERC1155 token address.")
                         .await?;
                 }
             } else {
@@ -268,17 +660,84 @@ async fn receive_user(
     msg: Message,
     dialogue: MyDialogue,
     state: Arc<RwLock<State>>,
-    (chain_id, token_address): (u32, Address), // Available from `ChatState::ReceiveTokenAddress`.
+    chains: Arc<Chains>,
+    (chain_id, token_address): (u32, Option<Address>), // Available from `ChatState::ReceiveTokenAddress`.
 ) -> HandlerResult {
     match msg.text().map(ToOwned::to_owned) {
-        Some(user_address) => {
-            let mut state = state.write().await;
-
-            if let Ok(user_address) = Address::from_str(&user_address) {
-                state.insert_sub(chain_id, token_address, user_address, msg.chat.id);
+        Some(text) => {
+            let (user_address, label) = split_label(&text);
 
-                bot.send_message(msg.chat.id, "Everything is set.").await?;
-                dialogue.exit().await?
+            if label
+                .as_ref()
+                .is_some_and(|label| label.chars().count() > MAX_LABEL_LEN)
+            {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Labels can not be longer than {} characters.",
+                        MAX_LABEL_LEN
+                    ),
+                )
+                .await?;
+            } else if user_address.eq_ignore_ascii_case(ANY) {
+                let Some(token_address) = token_address else {
+                    bot.send_message(
+                        msg.chat.id,
+                        "Watching every address requires a specific token. Please insert a valid address.",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+                let symbol = token_info(&*state.read().await, &chains, chain_id, token_address)
+                    .map(|(symbol, _)| symbol)
+                    .unwrap_or_default();
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Send the minimum amount of {} a transfer must reach to be notified about.",
+                        symbol
+                    ),
+                )
+                .await?;
+                dialogue
+                    .update(ChatState::ReceiveThreshold {
+                        chain_id,
+                        token_address,
+                        user_address: None,
+                        label,
+                        direction: Direction::Both,
+                    })
+                    .await?;
+            } else if let Ok(user_address) = Address::from_str(user_address) {
+                let mut choices: Vec<InlineKeyboardButton> = [
+                    (Direction::In, "Received"),
+                    (Direction::Out, "Sent"),
+                    (Direction::Both, "Both"),
+                ]
+                .into_iter()
+                .map(|(direction, text)| InlineKeyboardButton::callback(text, direction.as_str()))
+                .collect();
+                // The native currency is moved without allowances.
+                if token_address != Some(NATIVE) {
+                    choices.push(InlineKeyboardButton::callback(
+                        "Approvals",
+                        SubKind::Approvals.as_str(),
+                    ));
+                }
+                bot.send_message(
+                    msg.chat.id,
+                    "Notify about transfers received by the address, sent by it, or both? Or about the allowances it grants?",
+                )
+                .reply_markup(InlineKeyboardMarkup::new([choices]))
+                .await?;
+                dialogue
+                    .update(ChatState::ReceiveDirection {
+                        chain_id,
+                        token_address,
+                        user_address,
+                        label,
+                    })
+                    .await?;
             } else {
                 bot.send_message(
                     msg.chat.id,
@@ -296,24 +755,464 @@ async fn receive_user(
     Ok(())
 }
 
+async fn receive_direction(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: MyDialogue,
+    state: Arc<RwLock<State>>,
+    chains: Arc<Chains>,
+    (chain_id, token_address, user_address, label): (u32, Option<Address>, Address, Option<String>), // Available from `ChatState::ReceiveUser`.
+) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+    let Some(data) = q.data else {
+        return Ok(());
+    };
+    if data == SubKind::Approvals.as_str() {
+        let sub = Subscription {
+            id: 0,
+            chain_id,
+            token_address,
+            token_sender_receiver: Some(user_address),
+            label,
+            min_amount: None,
+            direction: Direction::Both,
+            kind: SubKind::Approvals,
+            event: None,
+            filter: None,
+        };
+        return save_subscription(bot, dialogue, &mut *state.write().await, sub).await;
+    }
+    let Ok(direction) = data.parse::<Direction>() else {
+        return Ok(());
+    };
+    let Some(token_address) = token_address else {
+        // Amounts of different tokens can not be compared, every transfer is notified.
+        return ask_filter(
+            bot,
+            dialogue,
+            ChatState::ReceiveFilter {
+                chain_id,
+                token_address: None,
+                user_address: Some(user_address),
+                label,
+                min_amount: None,
+                direction,
+            },
+        )
+        .await;
+    };
+    let symbol = token_info(&*state.read().await, &chains, chain_id, token_address)
+        .map(|(symbol, _)| symbol)
+        .unwrap_or_default();
+    bot.send_message(
+        dialogue.chat_id(),
+        format!(
+            "Send the minimum amount of {} to be notified about, or 0 to be notified about every transfer.",
+            symbol
+        ),
+    )
+    .await?;
+    dialogue
+        .update(ChatState::ReceiveThreshold {
+            chain_id,
+            token_address,
+            user_address: Some(user_address),
+            label,
+            direction,
+        })
+        .await?;
+    Ok(())
+}
+
+async fn receive_threshold(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    state: Arc<RwLock<State>>,
+    chains: Arc<Chains>,
+    (chain_id, token_address, user_address, label, direction): (
+        u32,
+        Address,
+        Option<Address>,
+        Option<String>,
+        Direction,
+    ), // Available from `ChatState::ReceiveDirection`.
+) -> HandlerResult {
+    let Some((_, decimals)) = token_info(&*state.read().await, &chains, chain_id, token_address)
+    else {
+        bot.send_message(
+            msg.chat.id,
+            "Token is no longer known, please /cancel and start over.",
+        )
+        .await?;
+        return Ok(());
+    };
+    let Some(min_amount) = msg
+        .text()
+        .and_then(|text| render::parse_amount(text.trim(), decimals))
+    else {
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "Invalid amount. Please send a number with at most {} decimals.",
+                decimals
+            ),
+        )
+        .await?;
+        return Ok(());
+    };
+    if user_address.is_none() && min_amount.is_zero() {
+        bot.send_message(
+            msg.chat.id,
+            "A minimum amount is required when watching every address.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    ask_filter(
+        bot,
+        dialogue,
+        ChatState::ReceiveFilter {
+            chain_id,
+            token_address: Some(token_address),
+            user_address,
+            label,
+            min_amount: (!min_amount.is_zero()).then_some(min_amount),
+            direction,
+        },
+    )
+    .await
+}
+
+/// Last step of transfer subscriptions, `next` holds everything asked so far.
+async fn ask_filter(bot: Bot, dialogue: MyDialogue, next: ChatState) -> HandlerResult {
+    bot.send_message(
+        dialogue.chat_id(),
+        format!(
+            "Optionally send a filter over the amount, from, to, token, block, counterparty and its label, e.g. amount > 10k and label not in [\"Binance 14\"], or \"{}\" to be notified about every transfer.",
+            NO_FILTER
+        ),
+    )
+    .await?;
+    dialogue.update(next).await?;
+    Ok(())
+}
+
+async fn receive_filter(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    state: Arc<RwLock<State>>,
+    (chain_id, token_address, user_address, label, min_amount, direction): (
+        u32,
+        Option<Address>,
+        Option<Address>,
+        Option<String>,
+        Option<U256>,
+        Direction,
+    ), // Available from `ChatState::ReceiveThreshold` or `ChatState::ReceiveDirection`.
+) -> HandlerResult {
+    let Some(text) = msg.text().map(str::trim) else {
+        bot.send_message(msg.chat.id, "Please send a filter.")
+            .await?;
+        return Ok(());
+    };
+    let filter = if text.eq_ignore_ascii_case(NO_FILTER) {
+        None
+    } else {
+        match text.parse::<TransferFilter>() {
+            Ok(filter) if user_address.is_none() && filter.uses_counterparty() => {
+                bot.send_message(
+                    msg.chat.id,
+                    "There is no counterparty when watching every address, please send another filter.",
+                )
+                .await?;
+                return Ok(());
+            }
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Invalid filter: {}. Please send it again, or \"{}\".",
+                        e, NO_FILTER
+                    ),
+                )
+                .await?;
+                return Ok(());
+            }
+        }
+    };
+    let sub = Subscription {
+        id: 0,
+        chain_id,
+        token_address,
+        token_sender_receiver: user_address,
+        label,
+        min_amount,
+        direction,
+        kind: SubKind::Transfers,
+        event: None,
+        filter,
+    };
+    save_subscription(bot, dialogue, &mut *state.write().await, sub).await
+}
+
+async fn receive_event_chain_id(
+    bot: Bot,
+    dialogue: MyDialogue,
+    q: CallbackQuery,
+    chains: Arc<Chains>,
+) -> HandlerResult {
+    let chain = q
+        .data
+        .and_then(|chain_id| chain_id.parse::<u32>().ok())
+        .and_then(|chain_id| chains.get(&chain_id))
+        .filter(|chain| chain.enabled);
+    if let Some(chain) = chain {
+        bot.send_message(
+            dialogue.chat_id(),
+            format!(
+                "You've selected {} chain.\n Please insert the contract address, optionally followed by a label.",
+                chain.name
+            ),
+        )
+        .await?;
+        dialogue
+            .update(ChatState::ReceiveEventContract { chain_id: chain.id })
+            .await?;
+    } else {
+        bot.send_message(dialogue.chat_id(), "Chain is no longer available.")
+            .await?;
+    }
+    Ok(())
+}
+
+async fn receive_event_contract(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    chain_id: u32, // Available from `ChatState::ReceiveEventChainId`.
+) -> HandlerResult {
+    let Some(text) = msg.text() else {
+        bot.send_message(msg.chat.id, "Please send the contract address.")
+            .await?;
+        return Ok(());
+    };
+    let (contract_address, label) = split_label(text);
+    if label
+        .as_ref()
+        .is_some_and(|label| label.chars().count() > MAX_LABEL_LEN)
+    {
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "Labels can not be longer than {} characters.",
+                MAX_LABEL_LEN
+            ),
+        )
+        .await?;
+    } else if let Ok(contract_address) = Address::from_str(contract_address) {
+        bot.send_message(
+            msg.chat.id,
+            "Send the event to be notified about, either as declared in Solidity, like \"event Paused(address account)\", or as its JSON ABI.",
+        )
+        .await?;
+        dialogue
+            .update(ChatState::ReceiveEventAbi {
+                chain_id,
+                contract_address,
+                label,
+            })
+            .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "Invalid address. Please insert a valid address.",
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn receive_event_abi(
+    bot: Bot,
+    msg: Message,
+    dialogue: MyDialogue,
+    state: Arc<RwLock<State>>,
+    (chain_id, contract_address, label): (u32, Address, Option<String>), // Available from `ChatState::ReceiveEventContract`.
+) -> HandlerResult {
+    let event = match msg.text().map(CustomEvent::parse) {
+        Some(Ok(event)) => event,
+        Some(Err(e)) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("Invalid event: {}. Please send it again.", e),
+            )
+            .await?;
+            return Ok(());
+        }
+        None => {
+            bot.send_message(msg.chat.id, "Please send the event.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let sub = Subscription {
+        id: 0,
+        chain_id,
+        token_address: Some(contract_address),
+        token_sender_receiver: None,
+        label,
+        min_amount: None,
+        direction: Direction::Both,
+        kind: SubKind::Events,
+        event: Some(event),
+        filter: None,
+    };
+    save_subscription(bot, dialogue, &mut *state.write().await, sub).await
+}
+
+/// Ends the dialogue once the subscription is stored.
+async fn save_subscription(
+    bot: Bot,
+    dialogue: MyDialogue,
+    state: &mut State,
+    sub: Subscription,
+) -> HandlerResult {
+    let text = match state.insert_sub(dialogue.chat_id(), sub) {
+        Ok(Saved::Created(id)) => format!("Everything is set. Subscription id: {}.", id),
+        Ok(Saved::Updated(id)) => format!("Subscription {} updated.", id),
+        Err(error) => {
+            bot.send_message(
+                dialogue.chat_id(),
+                format!("Unable to save the subscription: {}.", error),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    bot.send_message(dialogue.chat_id(), text).await?;
+    dialogue.exit().await?;
+    Ok(())
+}
+
+/// Address sent by the user, followed by its label if any.
+fn split_label(text: &str) -> (&str, Option<String>) {
+    match text.trim().split_once(char::is_whitespace) {
+        Some((address, label)) => (address, Some(label.trim().to_string())),
+        None => (text.trim(), None),
+    }
+}
+
+/// Whether the text names the native currency of the chain, either by keyword or by symbol.
+fn is_native(chains: &Chains, chain_id: u32, text: &str) -> bool {
+    text.eq_ignore_ascii_case(NATIVE_TOKEN)
+        || chains
+            .get(&chain_id)
+            .is_some_and(|chain| text.eq_ignore_ascii_case(&chain.native_symbol))
+}
+
+/// Symbol and decimals of a known token, those of the native currency come from
+/// the chain config.
+fn token_info(
+    state: &State,
+    chains: &Chains,
+    chain_id: u32,
+    token_address: Address,
+) -> Option<(String, u8)> {
+    if token_address == NATIVE {
+        return chains
+            .get(&chain_id)
+            .map(|chain| (chain.native_symbol.clone(), NATIVE_DECIMALS));
+    }
+    state
+        .get_token_metadata(&chain_id, &token_address)
+        .map(|(_, symbol, decimals)| (symbol.clone(), *decimals))
+}
+
 async fn fetch_token_metadata(rpc: &str, token_address: Address) -> Result<(String, String, u8)> {
-    abigen!(
-        IERC20,
-        r#"[
-            function symbol() public view returns (string memory)
-            function name() public view returns (string memory)
-            function decimals() public view virtual returns (uint8)
-        ]"#,
-    );
     let provider = Provider::<Ws>::connect(rpc).await?;
-    let client = Arc::new(provider);
-    let contract = IERC20::new(token_address, client);
-    if let Ok(name) = contract.name().call().await {
-        if let Ok(symbol) = contract.symbol().call().await {
-            if let Ok(decimals) = contract.decimals().call().await {
-                return Ok((name, symbol, decimals));
+    token::fetch_token_metadata(Arc::new(provider), token_address).await
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    async fn round_trip(storage: Arc<ErasedStorage<ChatState>>) {
+        let chat = ChatId(42);
+        let state = ChatState::ReceiveUser {
+            chain_id: 11155111,
+            token_address: Some(Address::from_low_u64_be(1)),
+        };
+        storage
+            .clone()
+            .update_dialogue(chat, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.clone().get_dialogue(chat).await.unwrap(),
+            Some(state)
+        );
+        storage.clone().remove_dialogue(chat).await.unwrap();
+        assert_eq!(storage.get_dialogue(chat).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_dialogue_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dialogues.db");
+        let storage = DialogueStorage::Sqlite(path.to_str().unwrap().to_string())
+            .open()
+            .await
+            .unwrap();
+        round_trip(storage).await;
+    }
+
+    #[test]
+    fn test_older_dialogues() {
+        let address = Address::from_low_u64_be(1);
+        let user = Address::from_low_u64_be(2);
+        // The token was always given before transfers of any token were supported.
+        let state: ChatState = serde_json::from_str(&format!(
+            r#"{{"ReceiveUser":{{"chain_id":1,"token_address":"{:#x}"}}}}"#,
+            address
+        ))
+        .unwrap();
+        assert_eq!(
+            state,
+            ChatState::ReceiveUser {
+                chain_id: 1,
+                token_address: Some(address),
             }
-        }
+        );
+        // The direction was asked for after the threshold was.
+        let state: ChatState = serde_json::from_str(&format!(
+            r#"{{"ReceiveThreshold":{{"chain_id":1,"token_address":"{:#x}","user_address":"{:#x}","label":null}}}}"#,
+            address, user
+        ))
+        .unwrap();
+        assert_eq!(
+            state,
+            ChatState::ReceiveThreshold {
+                chain_id: 1,
+                token_address: address,
+                user_address: Some(user),
+                label: None,
+                direction: Direction::Both,
+            }
+        );
+    }
+
+    /// Requires a running server, e.g. `docker run -p 6379:6379 redis`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_dialogue_storage() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let storage = DialogueStorage::Redis(url).open().await.unwrap();
+        round_trip(storage).await;
     }
-    return Err(eyre!("Contract Call failed"));
 }
@@ -1,155 +1,1534 @@
 use ethers::{
-    abi::AbiDecode,
-    providers::{Middleware, Provider, StreamExt, Ws},
-    types::{Address, Filter, U256},
+    abi::Token,
+    providers::{Middleware, Provider, SubscriptionStream, Ws},
+    types::{
+        Address, BlockNumber, Filter, GethDebugBuiltInTracerType, GethDebugTracerType,
+        GethDebugTracingOptions, Log, Transaction, TxHash, H256, U256,
+    },
+};
+use eyre::{eyre, Result};
+use futures::{
+    future,
+    stream::{self, select_all, BoxStream, SelectAll},
+    FutureExt, StreamExt,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use teloxide::{prelude::*, types::MessageId};
+use tokio::{
+    sync::RwLock,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    approval::{Approval, APPROVAL},
+    config::{BlockTag, ChainInfo, Confirmations, TraceMethod},
+    custom_event::{token_text, CustomEvent},
+    filter::TransferFields,
+    notifier::{Notification, Notifier},
+    render::{
+        format_amount, Allowance, Amount, ApprovalEvent, ContractEvent, ParamValue, PendingStatus,
+        TokenId, TransferEvent,
+    },
+    state::{Direction, State, Subscription, WatchSet},
+    store::LogKey,
+    token::{fetch_token_metadata, is_collection},
+    trace::{self, InternalTransfer},
+    transfer::{
+        signature_topic, RejectedLogs, Transfer, Transferred, NATIVE, NATIVE_DECIMALS, TRANSFER,
+        TRANSFER_BATCH, TRANSFER_SINGLE,
+    },
 };
-use std::sync::Arc;
-use teloxide::prelude::*;
-use tokio::sync::RwLock;
 
-use crate::state::{ChainInfo, State};
+/// Consecutive failed connections after which the operator is alerted.
+const ALERT_AFTER_FAILURES: u32 = 5;
+/// A connection that stayed up for this long resets the backoff.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Blocks requested per `eth_getLogs` call while backfilling.
+const BACKFILL_CHUNK: u64 = 2_000;
+/// Downtime longer than this many blocks is only partially backfilled.
+const MAX_BACKFILL: u64 = 100_000;
+/// The cursor trails the newest log by this many blocks, since logs coming from
+/// separate subscriptions may arrive slightly out of order.
+const CURSOR_LAG: u64 = 5;
+/// Sent notifications older than this many blocks are no longer followed up on reorgs.
+const REORG_WINDOW: u64 = 128;
+/// Blocks scanned for native transfers after a reconnection, each one is fetched
+/// with all its transactions.
+const MAX_NATIVE_BACKFILL: u64 = 128;
+/// Stands in for the log index in the key of native transfers, which have no log.
+/// Internal transfers of a transaction count down from the one below.
+pub const NATIVE_LOG_INDEX: u64 = u64::MAX;
+/// Set in the log index of the key of custom events, a log may be notified both
+/// as a transfer and as a custom event.
+const EVENT_KEY_BIT: u64 = 1 << 63;
+/// Transactions announced from the mempool and still not mined after this long
+/// are reported as dropped.
+const MEMPOOL_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// Time given to a token first seen in a log to answer its name, symbol and decimals.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Exponential backoff between reconnection attempts.
 #[derive(Debug)]
-struct TransferEvent {
-    chain_name: String,
-    tx_on_scanner: String,
-    token_on_scanner: String,
-    name: String,
-    from: Address,
-    sender_on_scanner: String,
-    to: Address,
-    receiver_on_scanner: String,
-    amount: String,
-}
-
-impl TransferEvent {
-    fn format(&self) -> String {
-        format!(
-            "
-        Tokens transfered on {}
-        Token: [{}]({})
-        From: [{:#x}]({})
-        To: [{:#x}]({})
-        Amount: {}
-        View tx on [explorer]({})
-        ",
-            self.chain_name,
-            self.name,
-            self.token_on_scanner,
-            self.from,
-            self.sender_on_scanner,
-            self.to,
-            self.receiver_on_scanner,
-            self.amount,
-            self.tx_on_scanner
+struct Backoff {
+    attempts: u32,
+    min: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            attempts: 0,
+            min,
+            max,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.min.saturating_mul(2u32.saturating_pow(self.attempts));
+        self.attempts += 1;
+        delay.min(self.max)
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Listens for transfers on `chain` forever. Whenever the connection fails or the
+/// stream ends, reconnects to the next rpc endpoint with exponential backoff.
+pub async fn listener(
+    chain: &ChainInfo,
+    state: Arc<RwLock<State>>,
+    notifier: Notifier,
+    rejected: RejectedLogs,
+    operator: Option<ChatId>,
+) {
+    let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+    let mut alerted = false;
+    // Outlives connections, a reorg can be reported by the next one.
+    let sent = SentNotifications::default();
+    let mempool = MempoolTransfers::default();
+
+    for rpc_url in chain.rpc_urls.iter().cycle() {
+        let started = Instant::now();
+        let mut chain_listener = ChainListener::new(
+            chain,
+            &state,
+            &notifier,
+            &rejected,
+            sent.clone(),
+            mempool.clone(),
+        );
+        let error = match listen(&mut chain_listener, rpc_url, operator, &mut alerted).await {
+            Ok(()) => eyre!("log stream ended"),
+            Err(e) => e,
+        };
+        if started.elapsed() >= STABLE_CONNECTION {
+            backoff.reset();
+        }
+
+        let delay = backoff.next_delay();
+        log::warn!(
+            "{}: {} on {}, reconnecting in {:?}",
+            chain.name,
+            error,
+            rpc_url,
+            delay
+        );
+        if backoff.attempts >= ALERT_AFTER_FAILURES && !alerted {
+            alert(
+                notifier.bot(),
+                operator,
+                format!(
+                    "{} listener failed {} times in a row, last error: {}",
+                    chain.name, backoff.attempts, error
+                ),
+            )
+            .await;
+            alerted = true;
+        }
+        sleep(delay).await;
+    }
+}
+
+/// Filters matching the transfers of `filter` sent or received by `addresses`.
+/// Topics of a single filter must all match, so each side gets its own filter, and
+/// ERC1155 events index the operator first, so their sides come one topic later.
+fn side_filters(filter: Filter, addresses: Vec<Address>) -> [Filter; 4] {
+    let transfer = filter.clone().event(TRANSFER);
+    let multi = filter.events([TRANSFER_SINGLE, TRANSFER_BATCH]);
+    [
+        transfer.clone().topic1(addresses.clone()),
+        transfer.topic2(addresses.clone()),
+        multi.clone().topic2(addresses.clone()),
+        multi.topic3(addresses),
+    ]
+}
+
+/// Filters matching transfers of watched tokens sent or received by watched addresses,
+/// transfers of any token for addresses watching every token, every transfer of
+/// tokens with token-wide subscriptions, approvals granted by watched owners and
+/// custom events of watched contracts.
+/// The native currency emits no logs, its transfers are found by scanning blocks.
+fn transfer_filters(watch: &WatchSet) -> Vec<Filter> {
+    let mut filters = Vec::new();
+    let tokens: Vec<Address> = watch
+        .tokens
+        .iter()
+        .copied()
+        .filter(|token| *token != NATIVE)
+        .collect();
+    if !tokens.is_empty() {
+        let addresses: Vec<Address> = watch.addresses.iter().copied().collect();
+        filters.extend(side_filters(Filter::new().address(tokens), addresses));
+    }
+    if !watch.any_token_addresses.is_empty() {
+        let addresses: Vec<Address> = watch.any_token_addresses.iter().copied().collect();
+        filters.extend(side_filters(Filter::new(), addresses));
+    }
+    let tokens: Vec<Address> = watch
+        .token_wide
+        .iter()
+        .copied()
+        .filter(|token| *token != NATIVE)
+        .collect();
+    if !tokens.is_empty() {
+        filters.push(Filter::new().address(tokens).events([
+            TRANSFER,
+            TRANSFER_SINGLE,
+            TRANSFER_BATCH,
+        ]));
+    }
+    if !watch.approval_owners.is_empty() {
+        let owners: Vec<Address> = watch.approval_owners.iter().copied().collect();
+        filters.push(Filter::new().event(APPROVAL).topic1(owners));
+    }
+    if !watch.event_contracts.is_empty() {
+        let contracts: Vec<Address> = watch.event_contracts.iter().copied().collect();
+        let topics: Vec<H256> = watch.event_topics.iter().copied().collect();
+        filters.push(Filter::new().address(contracts).topic0(topics));
+    }
+    filters
+}
+
+type LogStream<'a> = SelectAll<SubscriptionStream<'a, Ws, Log>>;
+
+async fn subscribe<'a>(client: &'a Provider<Ws>, watch: &WatchSet) -> Result<LogStream<'a>> {
+    let mut streams = Vec::new();
+    for filter in transfer_filters(watch) {
+        streams.push(client.subscribe_logs(&filter).await?);
+    }
+    Ok(select_all(streams))
+}
+
+/// New block numbers, confirming pending transfers and giving the blocks to scan
+/// for native ones.
+async fn subscribe_heads(client: &Provider<Ws>) -> Result<BoxStream<'_, u64>> {
+    Ok(client
+        .subscribe_blocks()
+        .await?
+        .filter_map(|block| future::ready(block.number.map(|number| number.as_u64())))
+        .boxed())
+}
+
+/// Transactions entering the mempool, none unless the chain opted in.
+async fn subscribe_mempool(
+    client: &Provider<Ws>,
+    enabled: bool,
+) -> Result<BoxStream<'_, Transaction>> {
+    if !enabled {
+        return Ok(stream::pending().boxed());
+    }
+    Ok(client.subscribe_full_pending_txs().await?.boxed())
+}
+
+/// Subscribes to new logs through `rpc_url`, catches up on the blocks missed since
+/// the last processed one and then handles new logs until the stream ends.
+/// Subscriptions are renewed whenever the watched tokens or addresses change.
+async fn listen(
+    listener: &mut ChainListener<'_>,
+    rpc_url: &str,
+    operator: Option<ChatId>,
+    alerted: &mut bool,
+) -> Result<()> {
+    let chain = listener.chain;
+    let client = Arc::new(Provider::<Ws>::connect(rpc_url).await?);
+    let mut changes = listener.state.read().await.watch_changes();
+    let mut watch = listener.state.read().await.get_watch_set(&chain.id);
+    // Subscribe before backfilling so logs mined meanwhile are buffered instead of lost.
+    let mut stream = subscribe(&client, &watch).await?;
+    let mut heads = subscribe_heads(&client).await?;
+    let mut mempool_txs = subscribe_mempool(&client, chain.mempool).await?;
+    listener.backfill(&client, &watch).await?;
+
+    log::info!(
+        "{}: listening for transfers of {} tokens, of any token for {} addresses, of {} whole tokens, for approvals of {} addresses and for events of {} contracts on {}",
+        chain.name,
+        watch.tokens.len(),
+        watch.any_token_addresses.len(),
+        watch.token_wide.len(),
+        watch.approval_owners.len(),
+        watch.event_contracts.len(),
+        rpc_url
+    );
+    if *alerted {
+        alert(
+            listener.notifier.bot(),
+            operator,
+            format!("{} listener recovered.", chain.name),
         )
+        .await;
+        *alerted = false;
     }
+
+    loop {
+        tokio::select! {
+            log = stream.next(), if !stream.is_empty() => {
+                let Some(log) = log else {
+                    return Ok(());
+                };
+                listener.on_log(&client, log).await;
+            }
+            head = heads.next() => {
+                let Some(head) = head else {
+                    return Err(eyre!("block stream ended"));
+                };
+                listener.on_head(&client, &watch, head).await?;
+            }
+            tx = mempool_txs.next() => {
+                let Some(tx) = tx else {
+                    return Err(eyre!("pending transaction stream ended"));
+                };
+                listener.on_mempool_tx(&client, tx).await;
+            }
+            changed = changes.changed() => {
+                changed?;
+                let new_watch = listener.state.read().await.get_watch_set(&chain.id);
+                if new_watch == watch {
+                    continue;
+                }
+                let new_stream = subscribe(&client, &new_watch).await?;
+                let mut old_stream = std::mem::replace(&mut stream, new_stream);
+                // Logs already received by the old subscriptions would be lost otherwise.
+                while let Some(Some(log)) = old_stream.next().now_or_never() {
+                    listener.on_log(&client, log).await;
+                }
+                watch = new_watch;
+                log::info!(
+                    "{}: now watching {} tokens, {} addresses, {} addresses on any token, {} whole tokens, approvals of {} addresses and events of {} contracts",
+                    chain.name,
+                    watch.tokens.len(),
+                    watch.addresses.len(),
+                    watch.any_token_addresses.len(),
+                    watch.token_wide.len(),
+                    watch.approval_owners.len(),
+                    watch.event_contracts.len()
+                );
+            }
+        }
+    }
+}
+
+/// Messages sent about a log, kept so they can be followed up if the log is
+/// reorged out of the chain.
+#[derive(Debug, Default)]
+struct SentNotification {
+    block: u64,
+    messages: Vec<(ChatId, MessageId)>,
 }
 
-pub async fn listener(chain: &ChainInfo, state: Arc<RwLock<State>>, bot: Bot) {
-    let client = Provider::<Ws>::connect(chain.ws).await.unwrap();
+type SentNotifications = Arc<Mutex<HashMap<LogKey, SentNotification>>>;
 
-    let erc20_transfer_filter = Filter::new().event("Transfer(address,address,uint256)");
+/// Transfer announced while its transaction was in the mempool, followed until
+/// the transaction is mined, dropped or replaced.
+struct MempoolTransfer {
+    sender: Address,
+    nonce: U256,
+    seen: Instant,
+    /// Block the transaction was mined in. The transfer is kept for a while
+    /// after, its log must not be notified again.
+    mined: Option<u64>,
+    token: Address,
+    event: TransferEvent,
+    users: Vec<(ChatId, Option<Direction>)>,
+    /// Announcements delivered so far, the ones to edit.
+    messages: Arc<Mutex<HashMap<ChatId, MessageId>>>,
+}
 
-    let mut stream = client.subscribe_logs(&erc20_transfer_filter).await.unwrap();
+type MempoolTransfers = Arc<Mutex<HashMap<TxHash, MempoolTransfer>>>;
 
-    while let Some(log) = stream.next().await {
-        let state = state.read().await;
-        if let Some((name, _, decimals)) = state.get_token_metadata(&chain.id, &log.address) {
-            let tx_on_scanner = format!(
-                "{}tx/{:#x}",
-                chain.scanner_url,
-                log.transaction_hash.unwrap()
-            );
-            let sender_on_scanner = format!(
-                "{}address/{:#x}",
-                chain.scanner_url,
-                Address::from(log.topics[1])
-            );
-            let receiver_on_scanner = format!(
-                "{}address/{:#x}",
-                chain.scanner_url,
-                Address::from(log.topics[2])
-            );
-            let token_on_scanner = format!("{}address/{:#x}", chain.scanner_url, log.address);
-            let amount_formated = format_amount(
-                U256::decode(log.data).unwrap_or_else(|_| U256::from_big_endian(&[0])),
-                *decimals,
+/// Logs held back until they are deep enough in the chain, by (block, log index).
+#[derive(Debug, Default)]
+struct PendingLogs {
+    logs: BTreeMap<(u64, u64), Log>,
+}
+
+impl PendingLogs {
+    fn insert(&mut self, log: Log) {
+        if let (Some(block), Some(log_index)) = (log.block_number, log.log_index) {
+            self.logs.insert((block.as_u64(), log_index.as_u64()), log);
+        }
+    }
+
+    fn remove(&mut self, log: &Log) -> bool {
+        match (log.block_number, log.log_index) {
+            (Some(block), Some(log_index)) => self
+                .logs
+                .remove(&(block.as_u64(), log_index.as_u64()))
+                .is_some(),
+            _ => false,
+        }
+    }
+
+    fn oldest_block(&self) -> Option<u64> {
+        self.logs.keys().next().map(|(block, _)| *block)
+    }
+
+    /// Removes and returns the logs included up to `block`.
+    fn confirm(&mut self, block: u64) -> Vec<Log> {
+        let unconfirmed = self.logs.split_off(&(block + 1, 0));
+        std::mem::replace(&mut self.logs, unconfirmed)
+            .into_values()
+            .collect()
+    }
+}
+
+/// Turns the logs of a chain into notifications, holding them back until they
+/// have enough confirmations and following up on the ones reorged out.
+struct ChainListener<'a> {
+    chain: &'a ChainInfo,
+    state: &'a RwLock<State>,
+    notifier: &'a Notifier,
+    rejected: &'a RejectedLogs,
+    sent: SentNotifications,
+    mempool: MempoolTransfers,
+    pending: PendingLogs,
+    cursor: u64,
+    /// Tokens whose metadata could not be fetched, their transfers are skipped.
+    unknown_tokens: HashSet<Address>,
+    /// Whether tokens without decimals are NFT collections.
+    collections: HashMap<Address, bool>,
+    /// Last block scanned for native transfers, None while none are watched.
+    native_scanned: Option<u64>,
+}
+
+impl<'a> ChainListener<'a> {
+    fn new(
+        chain: &'a ChainInfo,
+        state: &'a RwLock<State>,
+        notifier: &'a Notifier,
+        rejected: &'a RejectedLogs,
+        sent: SentNotifications,
+        mempool: MempoolTransfers,
+    ) -> Self {
+        Self {
+            chain,
+            state,
+            notifier,
+            rejected,
+            sent,
+            mempool,
+            pending: PendingLogs::default(),
+            cursor: 0,
+            unknown_tokens: HashSet::new(),
+            collections: HashMap::new(),
+            native_scanned: None,
+        }
+    }
+
+    /// Replays the logs emitted between the persisted cursor and the current head.
+    async fn backfill(&mut self, client: &Arc<Provider<Ws>>, watch: &WatchSet) -> Result<()> {
+        let chain = self.chain;
+        let head = client.get_block_number().await?.as_u64();
+        let cursor = self.state.read().await.get_chain_cursor(&chain.id);
+        let filters = transfer_filters(watch);
+        self.cursor = match cursor {
+            // First run, nothing was missed.
+            None => head,
+            // Nobody to notify about the missed blocks.
+            Some(_) if watch.is_empty() => head,
+            Some(cursor) => cursor,
+        };
+
+        let mut from = self.cursor + 1;
+        if head.saturating_sub(from) > MAX_BACKFILL {
+            from = head - MAX_BACKFILL;
+            log::warn!(
+                "{}: skipping blocks {} to {}, too far behind",
+                chain.name,
+                self.cursor + 1,
+                from - 1
             );
-            let parsed_event = TransferEvent {
-                chain_name: chain.name.to_owned(),
-                tx_on_scanner,
-                token_on_scanner,
-                name: name.clone(),
-                from: Address::from(log.topics[1]),
-                sender_on_scanner,
-                to: Address::from(log.topics[2]),
-                receiver_on_scanner,
-                amount: amount_formated,
+        }
+        if from <= head {
+            log::info!("{}: backfilling blocks {} to {}", chain.name, from, head);
+        }
+
+        while from <= head {
+            let to = (from + BACKFILL_CHUNK - 1).min(head);
+            let mut logs = Vec::new();
+            for filter in &filters {
+                logs.extend(
+                    client
+                        .get_logs(&filter.clone().from_block(from).to_block(to))
+                        .await?,
+                );
+            }
+            logs.sort_by_key(|log| (log.block_number, log.log_index));
+            for log in logs {
+                self.on_log(client, log).await;
+            }
+            from = to + 1;
+        }
+        self.state
+            .write()
+            .await
+            .set_chain_cursor(&chain.id, self.cursor)?;
+        self.on_head(client, watch, head).await
+    }
+
+    async fn on_log(&mut self, client: &Arc<Provider<Ws>>, log: Log) {
+        let Some(block) = log.block_number.map(|block| block.as_u64()) else {
+            return;
+        };
+        if log.removed == Some(true) {
+            self.on_removed(log).await;
+            return;
+        }
+        if self.chain.confirmations.is_instant() {
+            self.handle_log(client, log).await;
+        } else {
+            self.pending.insert(log);
+        }
+        self.advance_cursor(block.saturating_sub(CURSOR_LAG)).await;
+    }
+
+    /// Notifies the pending logs that became confirmed with the new `head`.
+    async fn on_head(
+        &mut self,
+        client: &Arc<Provider<Ws>>,
+        watch: &WatchSet,
+        head: u64,
+    ) -> Result<()> {
+        let confirmed = match self.chain.confirmations {
+            Confirmations::Blocks(blocks) => head.checked_sub(blocks),
+            Confirmations::Tag(tag) => {
+                let tag = match tag {
+                    BlockTag::Safe => BlockNumber::Safe,
+                    BlockTag::Finalized => BlockNumber::Finalized,
+                };
+                client
+                    .get_block(tag)
+                    .await?
+                    .and_then(|block| block.number)
+                    .map(|number| number.as_u64())
+            }
+        };
+        if let Some(confirmed) = confirmed {
+            for log in self.pending.confirm(confirmed) {
+                self.handle_log(client, log).await;
+            }
+            self.scan_native(client, watch, confirmed).await?;
+        }
+        self.follow_mempool(client).await;
+        self.mempool
+            .lock()
+            .expect("lock is never poisoned")
+            .retain(|_, transfer| {
+                transfer
+                    .mined
+                    .is_none_or(|block| block + REORG_WINDOW > head)
+            });
+        self.sent
+            .lock()
+            .expect("lock is never poisoned")
+            .retain(|_, sent| sent.block + REORG_WINDOW > head);
+        self.advance_cursor(head.saturating_sub(CURSOR_LAG)).await;
+        Ok(())
+    }
+
+    /// Notifies the native transfers of the blocks up to `block` not scanned yet.
+    async fn scan_native(
+        &mut self,
+        client: &Arc<Provider<Ws>>,
+        watch: &WatchSet,
+        block: u64,
+    ) -> Result<()> {
+        if !watch.watches_native() {
+            self.native_scanned = None;
+            return Ok(());
+        }
+        // Resumes from the cursor after a reconnection, like logs do.
+        let mut scanned = self
+            .native_scanned
+            .unwrap_or(self.cursor)
+            .max(block.saturating_sub(MAX_NATIVE_BACKFILL));
+        self.native_scanned = Some(scanned);
+        while scanned < block {
+            let number = scanned + 1;
+            let Some(full_block) = client.get_block_with_txs(number).await? else {
+                break;
             };
-            let parsed_event = parsed_event.format();
+            let tx_hashes: Vec<TxHash> = full_block.transactions.iter().map(|tx| tx.hash).collect();
+            for tx in full_block.transactions {
+                // Contract creations have no recipient.
+                let Some(to) = tx.to else {
+                    continue;
+                };
+                if tx.value.is_zero() {
+                    continue;
+                }
+                let transfer = Transfer {
+                    token: NATIVE,
+                    from: tx.from,
+                    to,
+                    transferred: Transferred::Amount(tx.value),
+                };
+                self.handle_transfer(client, transfer, (tx.hash, NATIVE_LOG_INDEX), number)
+                    .await;
+            }
+            for (tx_hash, index, transfer) in
+                self.internal_transfers(client, number, &tx_hashes).await
+            {
+                self.handle_transfer(client, transfer, internal_key(tx_hash, index), number)
+                    .await;
+            }
+            scanned = number;
+            self.native_scanned = Some(scanned);
+        }
+        Ok(())
+    }
+
+    /// Native transfers made by contracts in the block, when the chain is traced.
+    /// Tracing errors are only logged so that a node without traces does not
+    /// hold back the other notifications.
+    async fn internal_transfers(
+        &self,
+        client: &Arc<Provider<Ws>>,
+        block: u64,
+        tx_hashes: &[TxHash],
+    ) -> Vec<InternalTransfer> {
+        let traces = match self.chain.traces {
+            None => return Vec::new(),
+            Some(TraceMethod::TraceBlock) => client
+                .trace_block(block.into())
+                .await
+                .map(|traces| trace::from_parity(&traces)),
+            Some(TraceMethod::DebugTraceBlockByNumber) => {
+                let options = GethDebugTracingOptions {
+                    tracer: Some(GethDebugTracerType::BuiltInTracer(
+                        GethDebugBuiltInTracerType::CallTracer,
+                    )),
+                    ..Default::default()
+                };
+                client
+                    .debug_trace_block_by_number(Some(block.into()), options)
+                    .await
+                    .map(|traces| trace::from_geth(&traces, tx_hashes))
+            }
+        };
+        traces.unwrap_or_else(|e| {
+            log::warn!(
+                "{}: unable to trace block {}: {}",
+                self.chain.name,
+                block,
+                e
+            );
+            Vec::new()
+        })
+    }
 
-            if let Some(users) =
-                state.get_sub_users(&chain.id, &log.address, &Address::from(log.topics[2]))
+    /// Drops a reorged log if it was still pending, otherwise tells the users
+    /// notified about it that the transfer or event was reverted.
+    async fn on_removed(&mut self, log: Log) {
+        if self.pending.remove(&log) {
+            return;
+        }
+        let (Some(tx_hash), Some(log_index)) = (log.transaction_hash, log.log_index) else {
+            return;
+        };
+        let key = (tx_hash, log_index.as_u64());
+        for (key, what) in [(key, "transfer"), (event_key(key), "event")] {
+            if let Err(e) = self
+                .state
+                .write()
+                .await
+                .unmark_notified(&self.chain.id, &key)
             {
-                for user in users {
-                    let clone_bot = bot.clone();
-                    let user = user.clone();
-                    let message = parsed_event.clone();
-                    tokio::spawn(async move {
-                        clone_bot
-                            .send_message(user, message)
-                            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-                            .await
-                            .unwrap();
-                    });
+                log::warn!("{}: unable to forget notified log: {}", self.chain.name, e);
+            }
+            let sent = self
+                .sent
+                .lock()
+                .expect("lock is never poisoned")
+                .remove(&key);
+            for (user, message_id) in sent.map(|sent| sent.messages).unwrap_or_default() {
+                let notification = Notification::new(
+                    user,
+                    format!("The {} above was reverted by a chain reorganization.", what),
+                )
+                .reply_to(message_id);
+                self.notifier.send(notification).await;
+            }
+        }
+    }
+
+    /// Persists the cursor, keeping it behind the logs still waiting for confirmations.
+    async fn advance_cursor(&mut self, block: u64) {
+        let mut block = match self.pending.oldest_block() {
+            Some(oldest) => block.min(oldest.saturating_sub(1)),
+            None => block,
+        };
+        if let Some(scanned) = self.native_scanned {
+            block = block.min(scanned);
+        }
+        if block <= self.cursor {
+            return;
+        }
+        if let Err(e) = self
+            .state
+            .write()
+            .await
+            .set_chain_cursor(&self.chain.id, block)
+        {
+            log::warn!("{}: unable to save cursor: {}", self.chain.name, e);
+        }
+        self.cursor = block;
+    }
+
+    /// Fetches and caches the metadata of a token first seen in a log, tokens that
+    /// fail to answer are not asked again on this connection.
+    async fn token_metadata(
+        &mut self,
+        client: &Arc<Provider<Ws>>,
+        token_address: Address,
+    ) -> Option<(String, String, u8)> {
+        let chain = self.chain;
+        if token_address == NATIVE {
+            let symbol = chain.native_symbol.clone();
+            return Some((symbol.clone(), symbol, NATIVE_DECIMALS));
+        }
+        if let Some(metadata) = self
+            .state
+            .read()
+            .await
+            .get_token_metadata(&chain.id, &token_address)
+        {
+            return Some(metadata.clone());
+        }
+        if self.unknown_tokens.contains(&token_address) {
+            return None;
+        }
+        let metadata = timeout(
+            METADATA_TIMEOUT,
+            fetch_token_metadata(client.clone(), token_address),
+        )
+        .await
+        .map_err(|_| eyre!("timed out"))
+        .and_then(|metadata| metadata);
+        match metadata {
+            Ok((name, symbol, decimals)) => {
+                if let Err(e) = self.state.write().await.insert_token_metadata(
+                    &chain.id,
+                    token_address,
+                    name.clone(),
+                    symbol.clone(),
+                    decimals,
+                ) {
+                    log::warn!("{}: unable to save token metadata: {}", chain.name, e);
                 }
+                Some((name, symbol, decimals))
             }
-            if let Some(users) =
-                state.get_sub_users(&chain.id, &log.address, &Address::from(log.topics[1]))
-            {
-                for user in users {
-                    let clone_bot = bot.clone();
-                    let user = user.clone();
-                    let message = parsed_event.clone();
-                    tokio::spawn(async move {
-                        clone_bot
-                            .send_message(user, message)
-                            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-                            .await
-                            .unwrap();
-                    });
+            Err(e) => {
+                log::info!(
+                    "{}: skipping transfers of {:#x}, no token metadata: {}",
+                    chain.name,
+                    token_address,
+                    e
+                );
+                self.unknown_tokens.insert(token_address);
+                None
+            }
+        }
+    }
+
+    /// Asked once per token. Unanswered tokens count as collections until they
+    /// answer, their calls are left alone meanwhile.
+    async fn is_collection(&mut self, client: &Arc<Provider<Ws>>, token: Address) -> bool {
+        if let Some(is_collection) = self.collections.get(&token) {
+            return *is_collection;
+        }
+        match timeout(METADATA_TIMEOUT, is_collection(client.clone(), token)).await {
+            Ok(is_collection) => *self.collections.entry(token).or_insert(is_collection),
+            Err(_) => true,
+        }
+    }
+
+    async fn succeeded(&self, client: &Arc<Provider<Ws>>, tx_hash: TxHash) -> bool {
+        match client.get_transaction_receipt(tx_hash).await {
+            Ok(Some(receipt)) => receipt.status != Some(0.into()),
+            Ok(None) => false,
+            Err(e) => {
+                log::warn!(
+                    "{}: unable to fetch receipt of {:#x}: {}",
+                    self.chain.name,
+                    tx_hash,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    fn token_id(&self, token_address: Address, id: U256, amount: Option<U256>) -> TokenId {
+        TokenId {
+            id,
+            amount,
+            on_scanner: format!("{}nft/{:#x}/{}", self.chain.scanner_url, token_address, id),
+        }
+    }
+
+    async fn handle_log(&mut self, client: &Arc<Provider<Ws>>, log: Log) {
+        let chain = self.chain;
+        let (Some(tx_hash), Some(log_index), Some(block)) =
+            (log.transaction_hash, log.log_index, log.block_number)
+        else {
+            return;
+        };
+        let key = (tx_hash, log_index.as_u64());
+        let block = block.as_u64();
+        let custom = self.handle_custom_event(&log, key, block).await;
+        let reject = if log.topics.first() == Some(&signature_topic(APPROVAL)) {
+            match Approval::decode(&log) {
+                Ok(Some(approval)) => {
+                    self.handle_approval(client, approval, key, block).await;
+                    return;
+                }
+                // ERC721 approvals, well formed but not watched.
+                Ok(None) => return,
+                Err(reject) => reject,
+            }
+        } else {
+            match Transfer::decode(&log) {
+                Ok(transfer) => {
+                    self.handle_transfer(client, transfer, key, block).await;
+                    return;
+                }
+                Err(reject) => reject,
+            }
+        };
+        // Matched on the address of the contract only, not meant to be a transfer.
+        if custom {
+            return;
+        }
+        log::info!(
+            "{}: skipping log {} of {:#x} from {:#x}: {}",
+            chain.name,
+            log_index,
+            tx_hash,
+            log.address,
+            reject
+        );
+        self.rejected.record(chain.id, reject);
+    }
+
+    /// Chats subscribed to either side of `transfer`, or to all transfers of its token.
+    /// Filters of the subscriptions are only evaluated once the decimals of the
+    /// token are known, until then the chats using one are kept.
+    async fn matching_users(
+        &self,
+        transfer: &Transfer,
+        decimals: Option<u8>,
+        block: Option<u64>,
+    ) -> Vec<(ChatId, Option<Direction>)> {
+        let chain = self.chain;
+        let (from, to, token) = (transfer.from, transfer.to, transfer.token);
+        let amount = transfer.amount();
+        let passes = |sub: &Subscription, counterparty: Option<Address>| {
+            let (Some(filter), Some(decimals)) = (&sub.filter, decimals) else {
+                return true;
+            };
+            filter.matches(&TransferFields {
+                amount,
+                decimals,
+                from,
+                to,
+                token,
+                block,
+                counterparty,
+                label: counterparty
+                    .and_then(|counterparty| chain.labels.get(&counterparty))
+                    .map(String::as_str),
+            })
+        };
+        let passes = &passes;
+        let state = self.state.read().await;
+        let matches = [(to, from, Direction::In), (from, to, Direction::Out)]
+            .into_iter()
+            .flat_map(|(address, counterparty, direction)| {
+                state
+                    .get_matching_subs(chain.id, token, address)
+                    .filter(move |(_, sub)| {
+                        sub.direction.includes(direction)
+                            && sub.matches_amount(amount)
+                            && passes(sub, Some(counterparty))
+                    })
+                    .map(move |(user, _)| (*user, Some(direction)))
+            });
+        let token_wide = state
+            .get_token_subs(chain.id, token)
+            .iter()
+            .filter(|(_, sub)| sub.matches_amount(amount) && passes(sub, None))
+            .map(|(user, _)| (*user, None));
+        merge_matches(matches.chain(token_wide))
+    }
+
+    fn transfer_event(
+        &self,
+        transfer: Transfer,
+        tx_hash: TxHash,
+        name: String,
+        decimals: u8,
+    ) -> TransferEvent {
+        let chain = self.chain;
+        let (from, to, token) = (transfer.from, transfer.to, transfer.token);
+        let token_on_scanner = if token == NATIVE {
+            chain.scanner_url.clone()
+        } else {
+            format!("{}address/{:#x}", chain.scanner_url, token)
+        };
+        let amount = match transfer.transferred {
+            Transferred::Amount(amount) => Amount::Tokens(format_amount(amount, decimals)),
+            Transferred::Nft(id) => Amount::Ids(vec![self.token_id(token, id, None)]),
+            Transferred::Ids(ids) => Amount::Ids(
+                ids.into_iter()
+                    .map(|(id, amount)| self.token_id(token, id, Some(amount)))
+                    .collect(),
+            ),
+        };
+        TransferEvent {
+            direction: None,
+            chain_name: chain.name.clone(),
+            tx_on_scanner: format!("{}tx/{:#x}", chain.scanner_url, tx_hash),
+            token_on_scanner,
+            name,
+            from,
+            sender_on_scanner: format!("{}address/{:#x}", chain.scanner_url, from),
+            to,
+            receiver_on_scanner: format!("{}address/{:#x}", chain.scanner_url, to),
+            amount,
+        }
+    }
+
+    /// Records that `key` is being notified, false if it already was.
+    async fn mark_notified(&self, key: LogKey, block: u64) -> bool {
+        let chain = self.chain;
+        match self
+            .state
+            .write()
+            .await
+            .mark_notified(&chain.id, key, block)
+        {
+            Ok(notified) => notified,
+            Err(e) => {
+                log::warn!("{}: unable to record notified log: {}", chain.name, e);
+                true
+            }
+        }
+    }
+
+    /// Remembers the message delivered to `user` about `key`, to follow it up on reorgs.
+    fn track_sent(
+        &self,
+        key: LogKey,
+        block: u64,
+        user: ChatId,
+    ) -> impl FnOnce(MessageId) + Send + 'static {
+        let sent = self.sent.clone();
+        move |message_id| {
+            sent.lock()
+                .expect("lock is never poisoned")
+                .entry(key)
+                .or_insert_with(|| SentNotification {
+                    block,
+                    ..Default::default()
+                })
+                .messages
+                .push((user, message_id));
+        }
+    }
+
+    /// Notifies the chats watching the approvals of the owner, once per `key`.
+    async fn handle_approval(
+        &mut self,
+        client: &Arc<Provider<Ws>>,
+        approval: Approval,
+        key: LogKey,
+        block: u64,
+    ) {
+        let chain = self.chain;
+        let users = {
+            let state = self.state.read().await;
+            let mut users: Vec<ChatId> = Vec::new();
+            // A chat may watch the owner for this token and for any token.
+            for (user, _) in state.get_approval_subs(chain.id, approval.token, approval.owner) {
+                if !users.contains(user) {
+                    users.push(*user);
+                }
+            }
+            users
+        };
+        if users.is_empty() {
+            return;
+        }
+        let Some((name, _, decimals)) = self.token_metadata(client, approval.token).await else {
+            return;
+        };
+        if !self.mark_notified(key, block).await {
+            return;
+        }
+
+        let allowance = if approval.is_unlimited() {
+            Allowance::Unlimited
+        } else if approval.value.is_zero() {
+            Allowance::Revoked
+        } else {
+            Allowance::Limited(format_amount(approval.value, decimals))
+        };
+        let event = ApprovalEvent {
+            chain_name: chain.name.clone(),
+            tx_on_scanner: format!("{}tx/{:#x}", chain.scanner_url, key.0),
+            token_on_scanner: format!("{}address/{:#x}", chain.scanner_url, approval.token),
+            name,
+            owner: approval.owner,
+            owner_on_scanner: format!("{}address/{:#x}", chain.scanner_url, approval.owner),
+            spender: approval.spender,
+            spender_on_scanner: format!("{}address/{:#x}", chain.scanner_url, approval.spender),
+            spender_label: chain.labels.get(&approval.spender).cloned(),
+            allowance,
+        };
+        let format = self.notifier.format();
+        let text = event.render(format);
+        for user in users {
+            let notification = Notification::new(user, text.clone())
+                .parse_mode(format.parse_mode())
+                .on_sent(self.track_sent(key, block, user));
+            self.notifier.send(notification).await;
+        }
+    }
+
+    /// Notifies the chats subscribed to the event of `log`, once per log.
+    /// Returns whether the log is one of these events.
+    async fn handle_custom_event(&mut self, log: &Log, key: LogKey, block: u64) -> bool {
+        let chain = self.chain;
+        let Some(topic) = log.topics.first() else {
+            return false;
+        };
+        let subscribers: Vec<(ChatId, Option<String>, CustomEvent)> = self
+            .state
+            .read()
+            .await
+            .get_event_subs(chain.id, log.address)
+            .iter()
+            .filter_map(|(user, sub)| {
+                let event = sub.event.as_ref()?;
+                (event.topic() == *topic).then(|| (*user, sub.label.clone(), event.clone()))
+            })
+            .collect();
+        if subscribers.is_empty() {
+            return false;
+        }
+        let key = event_key(key);
+        if !self.mark_notified(key, block).await {
+            return true;
+        }
+
+        let format = self.notifier.format();
+        for (user, label, event) in subscribers {
+            // Chats may declare the same event with different parameter names
+            // or indexing, each one gets its own decoding.
+            let params = match event.decode(log) {
+                Ok(params) => params,
+                Err(e) => {
+                    log::info!(
+                        "{}: unable to decode {} of {:#x} in {:#x}: {}",
+                        chain.name,
+                        event.name(),
+                        log.address,
+                        key.0,
+                        e
+                    );
+                    continue;
+                }
+            };
+            let params = params
+                .into_iter()
+                .map(|(name, token)| {
+                    let value = match token {
+                        Token::Address(address) => ParamValue::Address(
+                            address,
+                            format!("{}address/{:#x}", chain.scanner_url, address),
+                        ),
+                        token => ParamValue::Text(token_text(&token)),
+                    };
+                    (name, value)
+                })
+                .collect();
+            let message = ContractEvent {
+                chain_name: chain.name.clone(),
+                tx_on_scanner: format!("{}tx/{:#x}", chain.scanner_url, key.0),
+                name: event.name().to_string(),
+                contract: log.address,
+                contract_on_scanner: format!("{}address/{:#x}", chain.scanner_url, log.address),
+                label,
+                params,
+            };
+            let notification = Notification::new(user, message.render(format))
+                .parse_mode(format.parse_mode())
+                .on_sent(self.track_sent(key, block, user));
+            self.notifier.send(notification).await;
+        }
+        true
+    }
+
+    /// Notifies the chats subscribed to either side of `transfer`, once per `key`.
+    async fn handle_transfer(
+        &mut self,
+        client: &Arc<Provider<Ws>>,
+        transfer: Transfer,
+        key: LogKey,
+        block: u64,
+    ) {
+        let token = transfer.token;
+        if self
+            .matching_users(&transfer, None, Some(block))
+            .await
+            .is_empty()
+        {
+            return;
+        }
+        let Some((name, _, decimals)) = self.token_metadata(client, token).await else {
+            return;
+        };
+        let mut users = self
+            .matching_users(&transfer, Some(decimals), Some(block))
+            .await;
+        // Chats told about the transfer while it was pending get their
+        // announcement edited instead.
+        let announced = self.announced_users(key.0, &transfer);
+        users.retain(|(user, _)| !announced.contains(user));
+        if users.is_empty() {
+            return;
+        }
+        // Reverted transactions keep their value, only receipts tell.
+        if token == NATIVE && !self.succeeded(client, key.0).await {
+            return;
+        }
+        if !self.mark_notified(key, block).await {
+            return;
+        }
+
+        let event = self.transfer_event(transfer, key.0, name, decimals);
+        let format = self.notifier.format();
+        let messages = render_by_direction(&users, &event, |event| event.render(format));
+
+        // Queued without holding the state lock, the queue may be full.
+        for (user, direction) in users {
+            let notification = Notification::new(user, messages[&direction].clone())
+                .parse_mode(format.parse_mode())
+                .on_sent(self.track_sent(key, block, user));
+            self.notifier.send(notification).await;
+        }
+    }
+
+    /// Chats `transfer` was announced to while its transaction was in the mempool.
+    fn announced_users(&self, tx_hash: TxHash, transfer: &Transfer) -> Vec<ChatId> {
+        self.mempool
+            .lock()
+            .expect("lock is never poisoned")
+            .get(&tx_hash)
+            .filter(|announced| {
+                announced.token == transfer.token
+                    && announced.event.from == transfer.from
+                    && announced.event.to == transfer.to
+            })
+            .map(|announced| announced.users.iter().map(|(user, _)| *user).collect())
+            .unwrap_or_default()
+    }
+
+    /// Announces a watched token transfer made by a transaction not mined yet.
+    async fn on_mempool_tx(&mut self, client: &Arc<Provider<Ws>>, tx: Transaction) {
+        let Some(token) = tx.to else {
+            return;
+        };
+        let Some(transfer) = Transfer::decode_call(token, tx.from, &tx.input) else {
+            return;
+        };
+        if self
+            .mempool
+            .lock()
+            .expect("lock is never poisoned")
+            .contains_key(&tx.hash)
+        {
+            return;
+        }
+        if self.matching_users(&transfer, None, None).await.is_empty() {
+            return;
+        }
+        let Some((name, _, decimals)) = self.token_metadata(client, token).await else {
+            return;
+        };
+        // Collections share the `transferFrom` selector, their ids would read as amounts.
+        if decimals == 0 && self.is_collection(client, token).await {
+            return;
+        }
+        let users = self.matching_users(&transfer, Some(decimals), None).await;
+        if users.is_empty() {
+            return;
+        }
+
+        let token = transfer.token;
+        let event = self.transfer_event(transfer, tx.hash, name, decimals);
+        let format = self.notifier.format();
+        let texts = render_by_direction(&users, &event, |event| {
+            event.render_pending(format, PendingStatus::Pending)
+        });
+        let messages = Arc::new(Mutex::new(HashMap::new()));
+        self.mempool.lock().expect("lock is never poisoned").insert(
+            tx.hash,
+            MempoolTransfer {
+                sender: tx.from,
+                nonce: tx.nonce,
+                seen: Instant::now(),
+                mined: None,
+                token,
+                event,
+                users: users.clone(),
+                messages: messages.clone(),
+            },
+        );
+        for (user, direction) in users {
+            let messages = messages.clone();
+            let notification = Notification::new(user, texts[&direction].clone())
+                .parse_mode(format.parse_mode())
+                .on_sent(move |message_id| {
+                    messages
+                        .lock()
+                        .expect("lock is never poisoned")
+                        .insert(user, message_id);
+                });
+            self.notifier.send(notification).await;
+        }
+    }
+
+    /// Edits the announcements of mempool transfers whose transaction was mined,
+    /// dropped or replaced since.
+    async fn follow_mempool(&mut self, client: &Arc<Provider<Ws>>) {
+        let followed: Vec<(TxHash, Address, U256, Instant)> = self
+            .mempool
+            .lock()
+            .expect("lock is never poisoned")
+            .iter()
+            .filter(|(_, transfer)| transfer.mined.is_none())
+            .map(|(tx_hash, transfer)| (*tx_hash, transfer.sender, transfer.nonce, transfer.seen))
+            .collect();
+        for (tx_hash, sender, nonce, seen) in followed {
+            let status = match mempool_status(client, tx_hash, sender, nonce, seen).await {
+                Ok(Some(status)) => status,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!(
+                        "{}: unable to follow {:#x}: {}",
+                        self.chain.name,
+                        tx_hash,
+                        e
+                    );
+                    continue;
                 }
+            };
+            let followed = {
+                let mut mempool = self.mempool.lock().expect("lock is never poisoned");
+                match status {
+                    PendingStatus::Mined(block) => mempool.get_mut(&tx_hash).map(|transfer| {
+                        transfer.mined = Some(block);
+                        (
+                            transfer.users.clone(),
+                            transfer.event.clone(),
+                            transfer.messages.clone(),
+                        )
+                    }),
+                    _ => mempool
+                        .remove(&tx_hash)
+                        .map(|transfer| (transfer.users, transfer.event, transfer.messages)),
+                }
+            };
+            let Some((users, event, messages)) = followed else {
+                continue;
+            };
+            let format = self.notifier.format();
+            let texts =
+                render_by_direction(&users, &event, |event| event.render_pending(format, status));
+            let messages = messages.lock().expect("lock is never poisoned").clone();
+            for (user, direction) in &users {
+                // Not delivered yet, or never.
+                let Some(message_id) = messages.get(user) else {
+                    continue;
+                };
+                let notification = Notification::new(*user, texts[direction].clone())
+                    .parse_mode(format.parse_mode())
+                    .edit(*message_id);
+                self.notifier.send(notification).await;
             }
         }
     }
 }
 
-fn format_amount(amount: U256, decimals: u8) -> String {
-    let units = decimals as usize;
-    let exp10 = U256::exp10(units);
+/// Where a transaction announced from the mempool stands, None while it is still pending.
+async fn mempool_status(
+    client: &Provider<Ws>,
+    tx_hash: TxHash,
+    sender: Address,
+    nonce: U256,
+    seen: Instant,
+) -> Result<Option<PendingStatus>> {
+    // Read before the receipt, a transaction mined in between would look replaced otherwise.
+    let mined_nonces = client.get_transaction_count(sender, None).await?;
+    if let Some(receipt) = client.get_transaction_receipt(tx_hash).await? {
+        let block = receipt.block_number.map_or(0, |block| block.as_u64());
+        return Ok(Some(if receipt.status == Some(0.into()) {
+            PendingStatus::Failed(block)
+        } else {
+            PendingStatus::Mined(block)
+        }));
+    }
+    if mined_nonces > nonce {
+        return Ok(Some(PendingStatus::Replaced));
+    }
+    if seen.elapsed() > MEMPOOL_TIMEOUT || client.get_transaction(tx_hash).await?.is_none() {
+        return Ok(Some(PendingStatus::Dropped));
+    }
+    Ok(None)
+}
 
-    let integer = amount / exp10;
-    let decimals = (amount % exp10).to_string();
+/// Key under which the internal transfer at `index` in a transaction is notified.
+pub fn internal_key(tx_hash: TxHash, index: u64) -> LogKey {
+    (tx_hash, NATIVE_LOG_INDEX - 1 - index)
+}
 
-    format!("{integer}\\.{decimals:0>units$}")
+/// Key under which the custom events of a log are notified.
+pub fn event_key((tx_hash, log_index): LogKey) -> LogKey {
+    (tx_hash, log_index | EVENT_KEY_BIT)
+}
+
+/// Renders `event` once per direction among `users`, chats in the same position
+/// share the text.
+fn render_by_direction(
+    users: &[(ChatId, Option<Direction>)],
+    event: &TransferEvent,
+    render: impl Fn(&TransferEvent) -> String,
+) -> HashMap<Option<Direction>, String> {
+    let mut texts = HashMap::new();
+    for (_, direction) in users {
+        texts.entry(*direction).or_insert_with(|| {
+            render(&TransferEvent {
+                direction: *direction,
+                ..event.clone()
+            })
+        });
+    }
+    texts
+}
+
+/// One entry per chat, a chat matching both sides of a transfer gets `Direction::Both`.
+/// Matches of token-wide subscriptions, without a direction, give way to the others.
+fn merge_matches(
+    matches: impl IntoIterator<Item = (ChatId, Option<Direction>)>,
+) -> Vec<(ChatId, Option<Direction>)> {
+    let mut users: Vec<(ChatId, Option<Direction>)> = Vec::new();
+    for (user, direction) in matches {
+        match users.iter_mut().find(|(merged, _)| *merged == user) {
+            Some((_, merged)) => {
+                *merged = match (*merged, direction) {
+                    (Some(merged), Some(direction)) if merged != direction => Some(Direction::Both),
+                    (merged, direction) => merged.or(direction),
+                }
+            }
+            None => users.push((user, direction)),
+        }
+    }
+    users
+}
+
+/// Logs `text` and forwards it to the operator chat, if one is configured.
+pub async fn alert(bot: &Bot, operator: Option<ChatId>, text: String) {
+    log::error!("{}", text);
+    if let Some(operator) = operator {
+        if let Err(e) = bot.send_message(operator, text).await {
+            log::error!("unable to alert operator: {}", e);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use ethers::{
+        types::{ValueOrArray, H256},
+        utils::keccak256,
+    };
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
 
     #[test]
-    fn test_format_amount() {
-        //131.55 ether
-        let amount = U256::from_dec_str("131550000000000000000").unwrap();
-        let formated = format_amount(amount, 18);
-        assert_eq!(formated, "131\\.550000000000000000".to_string());
-
-        //1.2
-        let amount = U256::from_dec_str("1200000").unwrap();
-        let formated = format_amount(amount, 6);
-        assert_eq!(formated, "1\\.200000".to_string());
+    fn test_transfer_filters() {
+        let mut watch = WatchSet::default();
+        assert!(transfer_filters(&watch).is_empty());
+
+        let token = Address::from_low_u64_be(1);
+        let user = Address::from_low_u64_be(2);
+        watch.tokens.insert(token);
+        watch.addresses.insert(user);
+        let filters = transfer_filters(&watch);
+        let watched = Some(ValueOrArray::Array(vec![Some(H256::from(user))]));
+        let sides: Vec<_> = filters.iter().map(|filter| &filter.topics[1..]).collect();
+        assert_eq!(
+            sides,
+            vec![
+                [watched.clone(), None, None],
+                [None, watched.clone(), None],
+                [None, watched.clone(), None],
+                [None, None, watched.clone()],
+            ]
+        );
+        assert!(filters
+            .iter()
+            .all(|filter| filter.address == Some(ValueOrArray::Array(vec![token]))));
+        let transfer = Some(ValueOrArray::Value(Some(H256::from(keccak256(TRANSFER)))));
+        assert_eq!(filters[0].topics[0], transfer);
+        assert_eq!(
+            filters[2].topics[0],
+            Some(ValueOrArray::Array(vec![
+                Some(H256::from(keccak256(TRANSFER_SINGLE))),
+                Some(H256::from(keccak256(TRANSFER_BATCH)))
+            ]))
+        );
+
+        let other = Address::from_low_u64_be(3);
+        watch.any_token_addresses.insert(other);
+        let filters = transfer_filters(&watch);
+        let watched = Some(ValueOrArray::Array(vec![Some(H256::from(other))]));
+        assert_eq!(filters.len(), 8);
+        assert_eq!(filters[4].address, None);
+        assert_eq!(filters[4].topics[1], watched);
+
+        watch.tokens.clear();
+        assert_eq!(transfer_filters(&watch).len(), 4);
+        watch.tokens.insert(NATIVE);
+        assert_eq!(transfer_filters(&watch).len(), 4);
+        watch.token_wide.insert(NATIVE);
+        assert_eq!(transfer_filters(&watch).len(), 4);
+
+        watch.token_wide.insert(token);
+        let filters = transfer_filters(&watch);
+        assert_eq!(filters.len(), 5);
+        assert_eq!(filters[4].address, Some(ValueOrArray::Array(vec![token])));
+        assert!(filters[4].topics[1..].iter().all(Option::is_none));
+
+        watch.approval_owners.insert(user);
+        let filters = transfer_filters(&watch);
+        assert_eq!(filters.len(), 6);
+        assert_eq!(filters[5].address, None);
+        assert_eq!(
+            filters[5].topics[0],
+            Some(ValueOrArray::Value(Some(H256::from(keccak256(APPROVAL)))))
+        );
+        assert_eq!(
+            filters[5].topics[1],
+            Some(ValueOrArray::Array(vec![Some(H256::from(user))]))
+        );
+
+        let contract = Address::from_low_u64_be(4);
+        let paused = CustomEvent::parse("Paused(address account)")
+            .unwrap()
+            .topic();
+        watch.event_contracts.insert(contract);
+        watch.event_topics.insert(paused);
+        let filters = transfer_filters(&watch);
+        assert_eq!(filters.len(), 7);
+        assert_eq!(
+            filters[6].address,
+            Some(ValueOrArray::Array(vec![contract]))
+        );
+        assert_eq!(
+            filters[6].topics[0],
+            Some(ValueOrArray::Array(vec![Some(paused)]))
+        );
+        assert!(filters[6].topics[1..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_merge_matches() {
+        let (alice, bob) = (ChatId(1), ChatId(2));
+        let (carol, dave) = (ChatId(3), ChatId(4));
+        let merged = merge_matches([
+            (alice, Some(Direction::In)),
+            (bob, Some(Direction::In)),
+            (carol, None),
+            (alice, Some(Direction::Out)),
+            (bob, Some(Direction::In)),
+            (carol, Some(Direction::Out)),
+            (dave, None),
+            (bob, None),
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                (alice, Some(Direction::Both)),
+                (bob, Some(Direction::In)),
+                (carol, Some(Direction::Out)),
+                (dave, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pending_logs() {
+        let log = |block: u64, log_index: u64| Log {
+            block_number: Some(block.into()),
+            log_index: Some(log_index.into()),
+            ..Default::default()
+        };
+        let mut pending = PendingLogs::default();
+        pending.insert(log(12, 0));
+        pending.insert(log(10, 3));
+        pending.insert(log(10, 1));
+        pending.insert(log(11, 0));
+        assert_eq!(pending.oldest_block(), Some(10));
+
+        assert!(pending.remove(&log(11, 0)));
+        assert!(!pending.remove(&log(11, 0)));
+
+        let confirmed: Vec<_> = pending
+            .confirm(11)
+            .into_iter()
+            .map(|log| log.log_index.unwrap().as_u64())
+            .collect();
+        assert_eq!(confirmed, vec![1, 3]);
+        assert_eq!(pending.oldest_block(), Some(12));
     }
 }
@@ -0,0 +1,261 @@
+use ethers::types::Address;
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+/// Upper bound for `confirmations`, anything above is most likely a typo.
+const MAX_CONFIRMATIONS: u64 = 1_000;
+
+/// How deep a transfer must be in the chain before users are notified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Confirmations {
+    /// Blocks mined on top of the one including the transfer, 0 notifies instantly.
+    Blocks(u64),
+    /// Wait until the node reports the block as `safe` or `finalized`.
+    Tag(BlockTag),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockTag {
+    Safe,
+    Finalized,
+}
+
+impl Default for Confirmations {
+    fn default() -> Self {
+        Confirmations::Blocks(0)
+    }
+}
+
+impl Confirmations {
+    pub fn is_instant(&self) -> bool {
+        *self == Confirmations::Blocks(0)
+    }
+}
+
+/// Node method used to find native transfers made by contracts, which only
+/// show up in execution traces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TraceMethod {
+    /// Parity style traces, served by Erigon, Nethermind, Reth and Anvil.
+    #[serde(rename = "trace_block")]
+    TraceBlock,
+    /// Geth's call tracer.
+    #[serde(rename = "debug_traceBlockByNumber")]
+    DebugTraceBlockByNumber,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChainInfo {
+    pub id: u32,
+    pub name: String,
+    pub scanner_url: String,
+    /// Websocket endpoints, the first one is preferred.
+    pub rpc_urls: Vec<String>,
+    #[serde(default)]
+    pub confirmations: Confirmations,
+    /// Symbol of the chain's own currency, which has 18 decimals.
+    #[serde(default = "eth")]
+    pub native_symbol: String,
+    /// Traces every block for internal native transfers, off by default since
+    /// most public nodes don't serve traces.
+    #[serde(default)]
+    pub traces: Option<TraceMethod>,
+    /// Announces token transfers still in the mempool, the node must stream
+    /// full pending transactions.
+    #[serde(default)]
+    pub mempool: bool,
+    /// Names of well known addresses, such as routers. Approvals to spenders
+    /// missing here are flagged.
+    #[serde(default)]
+    pub labels: BTreeMap<Address, String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn eth() -> String {
+    "ETH".to_string()
+}
+
+impl ChainInfo {
+    pub fn rpc_url(&self) -> &str {
+        &self.rpc_urls[0]
+    }
+}
+
+/// Registry of the chains the bot knows about, loaded from a TOML file.
+#[derive(Debug, Deserialize)]
+pub struct Chains {
+    chains: Vec<ChainInfo>,
+}
+
+impl Chains {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("unable to read {}: {}", path.display(), e))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut chains: Chains = toml::from_str(content)?;
+        chains.validate()?;
+        Ok(chains)
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        let mut ids = HashSet::new();
+        let mut names = HashSet::new();
+        for chain in self.chains.iter_mut() {
+            if !ids.insert(chain.id) {
+                return Err(eyre!("duplicated chain id {}", chain.id));
+            }
+            if chain.name.trim().is_empty() {
+                return Err(eyre!("chain {} has an empty name", chain.id));
+            }
+            if !names.insert(chain.name.clone()) {
+                return Err(eyre!("duplicated chain name {}", chain.name));
+            }
+            if !chain.scanner_url.starts_with("http://")
+                && !chain.scanner_url.starts_with("https://")
+            {
+                return Err(eyre!("{}: invalid scanner_url", chain.name));
+            }
+            if !chain.scanner_url.ends_with('/') {
+                chain.scanner_url.push('/');
+            }
+            if chain.native_symbol.trim().is_empty() {
+                return Err(eyre!("{}: native_symbol can not be empty", chain.name));
+            }
+            if chain.rpc_urls.is_empty() {
+                return Err(eyre!("{}: at least one rpc url is required", chain.name));
+            }
+            if let Some(url) = chain
+                .rpc_urls
+                .iter()
+                .find(|url| !url.starts_with("ws://") && !url.starts_with("wss://"))
+            {
+                return Err(eyre!("{}: {} is not a websocket url", chain.name, url));
+            }
+            if let Confirmations::Blocks(blocks) = chain.confirmations {
+                if blocks > MAX_CONFIRMATIONS {
+                    return Err(eyre!(
+                        "{}: confirmations can not exceed {}",
+                        chain.name,
+                        MAX_CONFIRMATIONS
+                    ));
+                }
+            }
+        }
+        if self.enabled().next().is_none() {
+            return Err(eyre!("no enabled chain"));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &u32) -> Option<&ChainInfo> {
+        self.chains.iter().find(|chain| chain.id == *id)
+    }
+
+    /// Chains users can subscribe to, in the order they appear in the file.
+    pub fn enabled(&self) -> impl Iterator<Item = &ChainInfo> {
+        self.chains.iter().filter(|chain| chain.enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [[chains]]
+        id = 11155111
+        name = "ETH Sepolia"
+        scanner_url = "https://sepolia.etherscan.io"
+        rpc_urls = ["wss://sepolia.gateway.tenderly.co"]
+
+        [[chains]]
+        id = 0
+        name = "Local"
+        scanner_url = "https://sepolia.etherscan.io/"
+        rpc_urls = ["ws://localhost:8545"]
+        confirmations = 2
+        native_symbol = "GO"
+        traces = "debug_traceBlockByNumber"
+        mempool = true
+        enabled = false
+
+        [chains.labels]
+        "0x000000000022D473030F116dDEE9F6B43aC78BA3" = "Permit2"
+    "#;
+
+    #[test]
+    fn test_parse_chains() {
+        let chains = Chains::parse(CONFIG).unwrap();
+        let sepolia = chains.get(&11155111).unwrap();
+        assert_eq!(sepolia.scanner_url, "https://sepolia.etherscan.io/");
+        assert_eq!(sepolia.confirmations, Confirmations::Blocks(0));
+        assert_eq!(sepolia.native_symbol, "ETH");
+        assert_eq!(sepolia.traces, None);
+        assert!(!sepolia.mempool);
+        let local = chains.get(&0).unwrap();
+        assert_eq!(local.confirmations, Confirmations::Blocks(2));
+        assert_eq!(local.native_symbol, "GO");
+        assert_eq!(local.traces, Some(TraceMethod::DebugTraceBlockByNumber));
+        assert!(local.mempool);
+        let permit2: Address = "0x000000000022D473030F116dDEE9F6B43aC78BA3"
+            .parse()
+            .unwrap();
+        assert_eq!(local.labels[&permit2], "Permit2");
+        assert!(sepolia.labels.is_empty());
+        assert!(!local.enabled);
+        assert_eq!(chains.enabled().count(), 1);
+    }
+
+    #[test]
+    fn test_default_config_is_valid() {
+        Chains::parse(include_str!("../chains.toml")).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_chains() {
+        let duplicated = CONFIG.replace("id = 0", "id = 11155111");
+        assert!(Chains::parse(&duplicated).is_err());
+
+        let http_rpc = CONFIG.replace("wss://", "https://");
+        assert!(Chains::parse(&http_rpc).is_err());
+
+        let no_rpc = CONFIG.replace(r#"["ws://localhost:8545"]"#, "[]");
+        assert!(Chains::parse(&no_rpc).is_err());
+
+        let finalized = CONFIG.replace("confirmations = 2", r#"confirmations = "finalized""#);
+        assert_eq!(
+            Chains::parse(&finalized)
+                .unwrap()
+                .get(&0)
+                .unwrap()
+                .confirmations,
+            Confirmations::Tag(BlockTag::Finalized)
+        );
+
+        let unknown_tag = CONFIG.replace("confirmations = 2", r#"confirmations = "latest""#);
+        assert!(Chains::parse(&unknown_tag).is_err());
+
+        let unknown_traces = CONFIG.replace("debug_traceBlockByNumber", "trace_replayBlock");
+        assert!(Chains::parse(&unknown_traces).is_err());
+
+        let none_enabled =
+            CONFIG.replace("rpc_urls = [\"wss", "enabled = false\nrpc_urls = [\"wss");
+        assert!(Chains::parse(&none_enabled).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use ethers::{
+    abi::{Abi, AbiParser, Event, RawLog, Token},
+    types::{Log, H256, I256},
+    utils::hex,
+};
+use eyre::{eyre, Result};
+
+/// Event of any contract, described by its Solidity declaration or its ABI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomEvent(Event);
+
+// Events only hold names, types and flags, all of them comparable.
+impl Eq for CustomEvent {}
+
+impl CustomEvent {
+    /// Parses `event Name(type indexed name, ...)`, the `event` keyword being
+    /// optional, or the JSON ABI of the event, alone or in an array holding no
+    /// other event. Anonymous events have no topic to be matched on.
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim().trim_end_matches(';');
+        let event = if text.starts_with('{') || text.starts_with('[') {
+            let json = if text.starts_with('{') {
+                format!("[{}]", text)
+            } else {
+                text.to_string()
+            };
+            let abi: Abi = serde_json::from_str(&json)?;
+            let mut events = abi.events();
+            match (events.next(), events.next()) {
+                (Some(event), None) => event.clone(),
+                (None, _) => return Err(eyre!("no event in the ABI")),
+                _ => return Err(eyre!("several events in the ABI, only one is expected")),
+            }
+        } else if text.starts_with("event ") {
+            AbiParser::default().parse_event(text)?
+        } else {
+            AbiParser::default().parse_event(&format!("event {}", text))?
+        };
+        if event.anonymous {
+            return Err(eyre!("anonymous events are not supported"));
+        }
+        Ok(Self(event))
+    }
+
+    /// How the event is stored along the subscription.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.0).expect("events always serialize")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(Self(serde_json::from_str(json)?))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Declaration of the event, as written in Solidity.
+    pub fn declaration(&self) -> String {
+        let inputs: Vec<String> = self
+            .0
+            .inputs
+            .iter()
+            .map(|input| {
+                let mut param = input.kind.to_string();
+                if input.indexed {
+                    param.push_str(" indexed");
+                }
+                if !input.name.is_empty() {
+                    param.push(' ');
+                    param.push_str(&input.name);
+                }
+                param
+            })
+            .collect();
+        format!("{}({})", self.0.name, inputs.join(", "))
+    }
+
+    /// Topic0 of the logs of the event.
+    pub fn topic(&self) -> H256 {
+        self.0.signature()
+    }
+
+    /// Parameters of `log`, in declaration order. Unnamed parameters are
+    /// named after their position.
+    pub fn decode(&self, log: &Log) -> Result<Vec<(String, Token)>> {
+        let decoded = self.0.parse_log(RawLog {
+            topics: log.topics.clone(),
+            data: log.data.to_vec(),
+        })?;
+        Ok(decoded
+            .params
+            .into_iter()
+            .enumerate()
+            .map(|(position, param)| {
+                let name = if param.name.is_empty() {
+                    position.to_string()
+                } else {
+                    param.name
+                };
+                (name, param.value)
+            })
+            .collect())
+    }
+}
+
+/// Plain text of a decoded parameter.
+pub fn token_text(token: &Token) -> String {
+    let list = |tokens: &[Token]| tokens.iter().map(token_text).collect::<Vec<_>>().join(", ");
+    match token {
+        Token::Address(address) => format!("{:#x}", address),
+        Token::FixedBytes(bytes) | Token::Bytes(bytes) => format!("0x{}", hex::encode(bytes)),
+        Token::Int(value) => I256::from_raw(*value).to_string(),
+        Token::Uint(value) => value.to_string(),
+        Token::Bool(value) => value.to_string(),
+        Token::String(value) => value.clone(),
+        Token::FixedArray(tokens) | Token::Array(tokens) => format!("[{}]", list(tokens)),
+        Token::Tuple(tokens) => format!("({})", list(tokens)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ethers::types::{Address, U256};
+
+    const ADDED_OWNER: &str = r#"{"anonymous":false,"inputs":[{"indexed":false,"internalType":"address","name":"owner","type":"address"}],"name":"AddedOwner","type":"event"}"#;
+
+    #[test]
+    fn test_parse() {
+        let declared = CustomEvent::parse("event AddedOwner(address owner);").unwrap();
+        assert_eq!(
+            CustomEvent::parse("AddedOwner(address owner)").unwrap(),
+            declared
+        );
+        assert_eq!(CustomEvent::parse(ADDED_OWNER).unwrap(), declared);
+        let abi = format!(
+            r#"[{{"type":"function","name":"addOwner","inputs":[],"outputs":[],"stateMutability":"nonpayable"}},{}]"#,
+            ADDED_OWNER
+        );
+        assert_eq!(CustomEvent::parse(&abi).unwrap(), declared);
+        assert_eq!(declared.name(), "AddedOwner");
+        assert_eq!(declared.declaration(), "AddedOwner(address owner)");
+        assert_eq!(
+            CustomEvent::from_json(&declared.to_json()).unwrap(),
+            declared
+        );
+
+        assert!(CustomEvent::parse("AddedOwner(addr owner)").is_err());
+        assert!(CustomEvent::parse("event Paused() anonymous").is_err());
+        assert!(CustomEvent::parse(&format!("[{0},{0}]", ADDED_OWNER)).is_err());
+        assert!(CustomEvent::parse("[]").is_err());
+        assert!(CustomEvent::parse("{").is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let event = CustomEvent::parse("Blacklisted(address indexed account, int256, bool frozen)")
+            .unwrap();
+        assert_eq!(
+            event.declaration(),
+            "Blacklisted(address indexed account, int256, bool frozen)"
+        );
+        let mut data = [0; 64];
+        U256::MAX.to_big_endian(&mut data[..32]);
+        data[63] = 1;
+        let account = Address::from_low_u64_be(2);
+        let log = Log {
+            address: Address::from_low_u64_be(1),
+            topics: vec![event.topic(), H256::from(account)],
+            data: data.to_vec().into(),
+            ..Default::default()
+        };
+        let params: Vec<_> = event
+            .decode(&log)
+            .unwrap()
+            .into_iter()
+            .map(|(name, token)| (name, token_text(&token)))
+            .collect();
+        assert_eq!(
+            params,
+            vec![
+                ("account".to_string(), format!("{:#x}", account)),
+                ("1".to_string(), "-1".to_string()),
+                ("frozen".to_string(), "true".to_string()),
+            ]
+        );
+
+        let mut other = log.clone();
+        other.topics[0] = H256::zero();
+        assert!(event.decode(&other).is_err());
+        let mut short = log;
+        short.data = data[..32].to_vec().into();
+        assert!(event.decode(&short).is_err());
+    }
+
+    #[test]
+    fn test_token_text() {
+        let tokens = Token::Tuple(vec![
+            Token::FixedBytes(vec![0xab, 0x01]),
+            Token::Array(vec![Token::Uint(1.into()), Token::Uint(2.into())]),
+            Token::String("paused".to_string()),
+        ]);
+        assert_eq!(token_text(&tokens), "(0xab01, [1, 2], paused)");
+    }
+}
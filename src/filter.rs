@@ -0,0 +1,647 @@
+use ethers::types::{Address, U256, U512};
+use eyre::{eyre, Result};
+use std::{fmt, str::FromStr};
+
+use crate::transfer::NATIVE;
+
+/// Longer expressions are refused, they are typed in a chat.
+const MAX_LEN: usize = 500;
+/// Deepest nesting of parentheses and `not`, keeps the parser off the stack limit.
+const MAX_DEPTH: usize = 16;
+/// Digits after the decimal point of an amount.
+const MAX_SCALE: usize = 36;
+
+/// Condition over the fields of a transfer a subscription must also meet, e.g.
+/// `amount > 10k and label not in ["Binance", "Coinbase"]`.
+///
+/// Fields are `amount`, in token units, `from`, `to`, `token`, `block`,
+/// `counterparty`, the other side of the transfer from the watched address, and
+/// `label`, the name the chain config gives to the counterparty. Conditions
+/// combine with `and`, `or`, `not` and parentheses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferFilter {
+    source: String,
+    expr: Expr,
+}
+
+/// Values a filter is evaluated against.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferFields<'a> {
+    /// In the token's smallest unit.
+    pub amount: U256,
+    pub decimals: u8,
+    pub from: Address,
+    pub to: Address,
+    pub token: Address,
+    /// None while the transaction is pending.
+    pub block: Option<u64>,
+    /// None for subscriptions watching no address.
+    pub counterparty: Option<Address>,
+    pub label: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cmp {
+    fn holds<T: Ord>(self, left: T, right: T) -> bool {
+        match self {
+            Cmp::Eq => left == right,
+            Cmp::Ne => left != right,
+            Cmp::Lt => left < right,
+            Cmp::Le => left <= right,
+            Cmp::Gt => left > right,
+            Cmp::Ge => left >= right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressField {
+    From,
+    To,
+    Token,
+    Counterparty,
+}
+
+/// `mantissa / 10^scale` tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Decimal {
+    mantissa: U256,
+    scale: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Amount(Cmp, Decimal),
+    Block(Cmp, u64),
+    /// Whether the field is one of the addresses, or none of them when negated.
+    Address(AddressField, bool, Vec<Address>),
+    /// Same for the label, compared ignoring case. A missing label is in no list.
+    Label(bool, Vec<String>),
+}
+
+impl TransferFilter {
+    pub fn matches(&self, fields: &TransferFields) -> bool {
+        self.expr.eval(fields)
+    }
+
+    /// Whether the filter refers to the counterparty, which subscriptions
+    /// watching no address don't have.
+    pub fn uses_counterparty(&self) -> bool {
+        self.expr.uses_counterparty()
+    }
+}
+
+impl FromStr for TransferFilter {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let source = s.trim();
+        if source.len() > MAX_LEN {
+            return Err(eyre!("longer than {} characters", MAX_LEN));
+        }
+        let mut parser = Parser {
+            tokens: lex(source)?,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(eyre!("unexpected {}", token));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for TransferFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expr {
+    fn eval(&self, fields: &TransferFields) -> bool {
+        match self {
+            Expr::And(left, right) => left.eval(fields) && right.eval(fields),
+            Expr::Or(left, right) => left.eval(fields) || right.eval(fields),
+            Expr::Not(expr) => !expr.eval(fields),
+            Expr::Amount(cmp, value) => {
+                // Both sides scaled to the smallest unit times 10^scale.
+                let amount = U512::from(fields.amount) * U512::exp10(value.scale.into());
+                let value = U512::from(10)
+                    .checked_pow(fields.decimals.into())
+                    .and_then(|unit| unit.checked_mul(U512::from(value.mantissa)))
+                    .unwrap_or(U512::MAX);
+                cmp.holds(amount, value)
+            }
+            Expr::Block(cmp, value) => fields.block.is_some_and(|block| cmp.holds(block, *value)),
+            Expr::Address(field, negated, addresses) => {
+                let address = match field {
+                    AddressField::From => Some(fields.from),
+                    AddressField::To => Some(fields.to),
+                    AddressField::Token => Some(fields.token),
+                    AddressField::Counterparty => fields.counterparty,
+                };
+                address.is_some_and(|address| addresses.contains(&address)) != *negated
+            }
+            Expr::Label(negated, labels) => {
+                fields.label.is_some_and(|label| {
+                    labels.iter().any(|other| other.eq_ignore_ascii_case(label))
+                }) != *negated
+            }
+        }
+    }
+
+    fn uses_counterparty(&self) -> bool {
+        match self {
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                left.uses_counterparty() || right.uses_counterparty()
+            }
+            Expr::Not(expr) => expr.uses_counterparty(),
+            Expr::Address(field, _, _) => *field == AddressField::Counterparty,
+            Expr::Label(_, _) => true,
+            Expr::Amount(_, _) | Expr::Block(_, _) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Number(String),
+    Text(String),
+    Cmp(Cmp),
+    Open,
+    Close,
+    OpenList,
+    CloseList,
+    Comma,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) | Token::Number(word) | Token::Text(word) => {
+                write!(f, "\"{}\"", word)
+            }
+            Token::Cmp(cmp) => f.write_str(match cmp {
+                Cmp::Eq => "\"==\"",
+                Cmp::Ne => "\"!=\"",
+                Cmp::Lt => "\"<\"",
+                Cmp::Le => "\"<=\"",
+                Cmp::Gt => "\">\"",
+                Cmp::Ge => "\">=\"",
+            }),
+            Token::Open => f.write_str("\"(\""),
+            Token::Close => f.write_str("\")\""),
+            Token::OpenList => f.write_str("\"[\""),
+            Token::CloseList => f.write_str("\"]\""),
+            Token::Comma => f.write_str("\",\""),
+        }
+    }
+}
+
+fn lex(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '[' => Token::OpenList,
+            ']' => Token::CloseList,
+            ',' => Token::Comma,
+            '=' | '!' | '<' | '>' => {
+                let equals = chars.next_if(|(_, next)| *next == '=').is_some();
+                Token::Cmp(match (c, equals) {
+                    ('=', true) => Cmp::Eq,
+                    ('!', true) => Cmp::Ne,
+                    ('<', false) => Cmp::Lt,
+                    ('<', true) => Cmp::Le,
+                    ('>', false) => Cmp::Gt,
+                    ('>', true) => Cmp::Ge,
+                    _ => return Err(eyre!("unknown operator \"{}\"", c)),
+                })
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => text.push(c),
+                        None => return Err(eyre!("unterminated text")),
+                    }
+                }
+                Token::Text(text)
+            }
+            c if c.is_ascii_alphanumeric() || c == '.' || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((index, next)) = chars.next_if(|(_, next)| {
+                    next.is_ascii_alphanumeric() || *next == '.' || *next == '_'
+                }) {
+                    end = index + next.len_utf8();
+                }
+                let word = &source[start..end];
+                if c.is_ascii_digit() || c == '.' {
+                    Token::Number(word.to_ascii_lowercase())
+                } else {
+                    Token::Word(word.to_ascii_lowercase())
+                }
+            }
+            c => return Err(eyre!("unexpected \"{}\"", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over `or := and ("or" and)*`, `and := unary ("and" unary)*`,
+/// `unary := "not" unary | "(" or ")" | condition`.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or(eyre!("unexpected end"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn next_if_word(&mut self, word: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(next)) if next == word);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(eyre!("expected {}, found {}", expected, token)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.next_if_word("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.next_if_word("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(eyre!("nested more than {} levels deep", MAX_DEPTH));
+        }
+        let expr = if self.next_if_word("not") {
+            Expr::Not(Box::new(self.unary()?))
+        } else if self.peek() == Some(&Token::Open) {
+            self.position += 1;
+            let expr = self.or()?;
+            self.expect(Token::Close)?;
+            expr
+        } else {
+            self.condition()?
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn condition(&mut self) -> Result<Expr> {
+        let field = match self.next()? {
+            Token::Word(field) => field,
+            token => return Err(eyre!("expected a field, found {}", token)),
+        };
+        match field.as_str() {
+            "amount" => Ok(Expr::Amount(self.cmp()?, self.amount()?)),
+            "block" => {
+                let cmp = self.cmp()?;
+                match self.next()? {
+                    Token::Number(number) => Ok(Expr::Block(
+                        cmp,
+                        number
+                            .parse()
+                            .map_err(|_| eyre!("invalid block {}", number))?,
+                    )),
+                    token => Err(eyre!("expected a block number, found {}", token)),
+                }
+            }
+            "from" | "to" | "token" | "counterparty" => {
+                let field = match field.as_str() {
+                    "from" => AddressField::From,
+                    "to" => AddressField::To,
+                    "token" => AddressField::Token,
+                    _ => AddressField::Counterparty,
+                };
+                let (negated, addresses) = self.membership(Self::address)?;
+                Ok(Expr::Address(field, negated, addresses))
+            }
+            "label" => {
+                let (negated, labels) = self.membership(|parser| match parser.next()? {
+                    Token::Text(text) => Ok(text),
+                    token => Err(eyre!("expected a quoted label, found {}", token)),
+                })?;
+                Ok(Expr::Label(negated, labels))
+            }
+            _ => Err(eyre!(
+                "unknown field \"{}\", expected amount, from, to, token, block, counterparty or label",
+                field
+            )),
+        }
+    }
+
+    fn cmp(&mut self) -> Result<Cmp> {
+        match self.next()? {
+            Token::Cmp(cmp) => Ok(cmp),
+            token => Err(eyre!("expected a comparison, found {}", token)),
+        }
+    }
+
+    /// `== value`, `!= value`, `in [values]` or `not in [values]`, returning
+    /// whether the condition is negated.
+    fn membership<T>(
+        &mut self,
+        mut value: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<(bool, Vec<T>)> {
+        match self.next()? {
+            Token::Cmp(Cmp::Eq) => Ok((false, vec![value(self)?])),
+            Token::Cmp(Cmp::Ne) => Ok((true, vec![value(self)?])),
+            Token::Word(word) if word == "in" || word == "not" => {
+                let negated = word == "not";
+                if negated && !self.next_if_word("in") {
+                    return Err(eyre!("expected \"in\" after \"not\""));
+                }
+                self.expect(Token::OpenList)?;
+                let mut values = vec![value(self)?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.position += 1;
+                    values.push(value(self)?);
+                }
+                self.expect(Token::CloseList)?;
+                Ok((negated, values))
+            }
+            token => Err(eyre!("expected ==, !=, in or not in, found {}", token)),
+        }
+    }
+
+    fn address(&mut self) -> Result<Address> {
+        match self.next()? {
+            Token::Word(word) if word == "native" => Ok(NATIVE),
+            Token::Number(number) => {
+                Address::from_str(&number).map_err(|_| eyre!("invalid address {}", number))
+            }
+            token => Err(eyre!("expected an address, found {}", token)),
+        }
+    }
+
+    /// Decimal number of tokens, optionally followed by `k`, `m` or `b`.
+    fn amount(&mut self) -> Result<Decimal> {
+        let number = match self.next()? {
+            Token::Number(number) => number,
+            token => return Err(eyre!("expected an amount, found {}", token)),
+        };
+        let invalid = || eyre!("invalid amount {}", number);
+        let (digits, exponent) = match number.char_indices().last() {
+            Some((index, 'k')) => (&number[..index], 3),
+            Some((index, 'm')) => (&number[..index], 6),
+            Some((index, 'b')) => (&number[..index], 9),
+            _ => (number.as_str(), 0),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if (integer.is_empty() && fraction.is_empty())
+            || !integer.chars().all(|c| c.is_ascii_digit())
+            || !fraction.chars().all(|c| c.is_ascii_digit())
+            || fraction.len() > MAX_SCALE
+        {
+            return Err(invalid());
+        }
+        let mut mantissa =
+            U256::from_dec_str(&format!("0{}{}", integer, fraction)).map_err(|_| invalid())?;
+        let mut scale = fraction.len();
+        if exponent >= scale {
+            mantissa = mantissa
+                .checked_mul(U256::exp10(exponent - scale))
+                .ok_or_else(invalid)?;
+            scale = 0;
+        } else {
+            scale -= exponent;
+        }
+        Ok(Decimal {
+            mantissa,
+            scale: scale as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use proptest::prelude::*;
+
+    fn address(byte: u64) -> Address {
+        Address::from_low_u64_be(byte)
+    }
+
+    fn fields(amount: u64) -> TransferFields<'static> {
+        TransferFields {
+            amount: U256::from(amount) * U256::exp10(6),
+            decimals: 6,
+            from: address(1),
+            to: address(2),
+            token: address(3),
+            block: Some(100),
+            counterparty: Some(address(2)),
+            label: Some("Binance 14"),
+        }
+    }
+
+    fn matches(filter: &str, fields: &TransferFields) -> bool {
+        filter.parse::<TransferFilter>().unwrap().matches(fields)
+    }
+
+    #[test]
+    fn test_amount() {
+        let transfer = fields(12_500);
+        assert!(matches("amount > 10k", &transfer));
+        assert!(matches("amount >= 12.5k", &transfer));
+        assert!(!matches("amount > 12.5k", &transfer));
+        assert!(matches("amount == 12500.000", &transfer));
+        assert!(matches("amount < 0.1m", &transfer));
+        assert!(!matches("amount < .1", &transfer));
+        let dust = TransferFields {
+            amount: U256::from(1),
+            ..transfer
+        };
+        assert!(matches("amount == 0.000001", &dust));
+        assert!(matches("amount < 0.0000011", &dust));
+        let nft = TransferFields {
+            amount: U256::from(2),
+            decimals: 0,
+            ..transfer
+        };
+        assert!(matches("amount > 1.5 and amount <= 2", &nft));
+        let odd = TransferFields {
+            decimals: 200,
+            ..transfer
+        };
+        assert!(matches("amount < 1", &odd));
+    }
+
+    #[test]
+    fn test_addresses_and_labels() {
+        let transfer = fields(1);
+        let to = format!("{:#x}", address(2));
+        assert!(matches(&format!("to == {}", to), &transfer));
+        assert!(!matches(&format!("from == {}", to), &transfer));
+        assert!(matches(
+            &format!("counterparty in [{}, {:#x}]", to, address(9)),
+            &transfer
+        ));
+        assert!(!matches(
+            &format!("counterparty not in [{}]", to),
+            &transfer
+        ));
+        assert!(matches("token != native", &transfer));
+        assert!(matches("label in [\"binance 14\", \"Kraken\"]", &transfer));
+        assert!(!matches("label not in [\"Binance 14\"]", &transfer));
+        let unlabeled = TransferFields {
+            label: None,
+            ..transfer
+        };
+        assert!(!matches("label == \"Binance 14\"", &unlabeled));
+        assert!(matches("label not in [\"Binance 14\"]", &unlabeled));
+        let token_wide = TransferFields {
+            counterparty: None,
+            ..transfer
+        };
+        assert!(!matches(&format!("counterparty == {}", to), &token_wide));
+        assert!(matches(&format!("counterparty != {}", to), &token_wide));
+    }
+
+    #[test]
+    fn test_logic() {
+        let transfer = fields(20_000);
+        assert!(matches(
+            "amount > 10k and label not in [\"Kraken\"] or block < 10",
+            &transfer
+        ));
+        // `and` binds tighter than `or`.
+        assert!(matches(
+            "block < 10 and amount > 1 or amount > 1",
+            &transfer
+        ));
+        assert!(!matches(
+            "block < 10 and (amount > 1 or amount > 1)",
+            &transfer
+        ));
+        assert!(matches("not block < 10", &transfer));
+        assert!(matches("not not (block >= 100)", &transfer));
+        let pending = TransferFields {
+            block: None,
+            ..transfer
+        };
+        assert!(!matches("block > 10", &pending));
+        assert!(!matches("block != 10", &pending));
+    }
+
+    #[test]
+    fn test_uses_counterparty() {
+        let uses = |filter: &str| {
+            filter
+                .parse::<TransferFilter>()
+                .unwrap()
+                .uses_counterparty()
+        };
+        assert!(!uses("amount > 1 and token == native"));
+        assert!(uses("amount > 1 or not label == \"x\""));
+        assert!(uses(&format!("counterparty != {:#x}", address(1))));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = |filter: &str| filter.parse::<TransferFilter>().unwrap_err().to_string();
+        assert_eq!(error("amount >"), "unexpected end");
+        assert_eq!(
+            error("value > 1"),
+            "unknown field \"value\", expected amount, from, to, token, block, counterparty or label"
+        );
+        assert_eq!(
+            error("amount in [1]"),
+            "expected a comparison, found \"in\""
+        );
+        assert_eq!(
+            error("from > 0x01"),
+            "expected ==, !=, in or not in, found \">\""
+        );
+        assert_eq!(error("from == 0x01"), "invalid address 0x01");
+        assert_eq!(error("amount > 1x"), "invalid amount 1x");
+        assert_eq!(error("amount > 1 amount"), "unexpected \"amount\"");
+        assert_eq!(error("(amount > 1"), "unexpected end");
+        assert_eq!(
+            error("label == binance"),
+            "expected a quoted label, found \"binance\""
+        );
+        assert_eq!(error("label == \"binance"), "unterminated text");
+        assert_eq!(error("amount => 1"), "unknown operator \"=\"");
+        assert_eq!(error("amount > 1 & block > 1"), "unexpected \"&\"");
+        assert_eq!(
+            error(&format!("{}amount > 1", "not ".repeat(MAX_DEPTH))),
+            format!("nested more than {} levels deep", MAX_DEPTH)
+        );
+        assert_eq!(
+            error(&format!("amount > {}", "1".repeat(MAX_LEN))),
+            format!("longer than {} characters", MAX_LEN)
+        );
+        assert!("amount > 1".parse::<TransferFilter>().is_ok());
+        assert_eq!(
+            " amount>1 ".parse::<TransferFilter>().unwrap().to_string(),
+            "amount>1"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_never_panics(source in "[a-z0-9 ()\\[\\],.=!<>\"x]{0,80}") {
+            if let Ok(filter) = source.parse::<TransferFilter>() {
+                filter.matches(&fields(1));
+            }
+        }
+    }
+}
@@ -1,31 +1,120 @@
-use eyre::Result;
-use std::sync::Arc;
-use teloxide::prelude::Bot;
-use tokio::sync::RwLock;
+use eyre::{eyre, Result};
+use std::{sync::Arc, time::Duration};
+use teloxide::{prelude::Bot, types::ChatId};
+use tokio::{sync::RwLock, time::sleep};
 
+mod approval;
 mod bot;
 mod chain_listener;
+mod config;
+mod custom_event;
+mod filter;
+mod notifier;
+mod render;
 mod state;
+mod store;
+mod token;
+mod trace;
+mod transfer;
 
-use state::{State, CHAINS_INFO};
+use bot::{DialogueStorage, Operator};
+use config::{ChainInfo, Chains};
+use notifier::Notifier;
+use render::Format;
+use state::State;
+use store::SqliteStore;
+use transfer::RejectedLogs;
+
+/// Delay before restarting a chain listener that panicked.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/// Notifications waiting for delivery before the chain listeners are slowed down.
+const NOTIFICATION_QUEUE: usize = 10_000;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenvy::dotenv()?;
+    pretty_env_logger::init();
 
     let api_key = dotenvy::var("TELOXIDE_TOKEN").expect("valid key exists in .env");
     let bot = Bot::new(api_key);
-    let state = Arc::new(RwLock::new(State::new()));
-
-    for chain in CHAINS_INFO.values() {
-        let clone_bot = bot.clone();
-        let state_clone = state.clone();
-        tokio::spawn(async move {
-            chain_listener::listener(chain, state_clone, clone_bot).await;
-        });
+    let chains_path = dotenvy::var("CHAINS_CONFIG").unwrap_or("chains.toml".to_string());
+    let chains = Arc::new(Chains::load(chains_path)?);
+    let database_path = dotenvy::var("DATABASE_PATH").unwrap_or("transferbot.db".to_string());
+    let store = SqliteStore::open(&database_path)?;
+    let state = Arc::new(RwLock::new(State::new(Box::new(store))));
+
+    let operator = match dotenvy::var("OPERATOR_CHAT_ID") {
+        Ok(id) => Some(ChatId(id.parse()?)),
+        Err(_) => None,
+    };
+
+    let format = match dotenvy::var("MESSAGE_FORMAT").as_deref() {
+        Ok("markdown") | Err(_) => Format::MarkdownV2,
+        Ok("html") => Format::Html,
+        Ok(other) => return Err(eyre!("unknown MESSAGE_FORMAT: {}", other)),
+    };
+    let notifier = Notifier::spawn(bot.clone(), state.clone(), format, NOTIFICATION_QUEUE);
+    let rejected = RejectedLogs::default();
+    for chain in chains.enabled() {
+        tokio::spawn(supervise(
+            chain.clone(),
+            state.clone(),
+            notifier.clone(),
+            rejected.clone(),
+            operator,
+        ));
     }
 
-    bot::run(bot, state).await;
+    let dialogue_storage = match dotenvy::var("DIALOGUE_STORAGE").as_deref() {
+        Ok("sqlite") | Err(_) => DialogueStorage::Sqlite(database_path),
+        Ok("redis") => DialogueStorage::Redis(dotenvy::var("REDIS_URL")?),
+        Ok("memory") => DialogueStorage::Memory,
+        Ok(other) => return Err(eyre!("unknown DIALOGUE_STORAGE: {}", other)),
+    };
+
+    bot::run(
+        bot,
+        state,
+        chains,
+        notifier,
+        rejected,
+        Operator(operator),
+        dialogue_storage.open().await?,
+    )
+    .await;
 
     Ok(())
 }
+
+/// Runs the chain listener in its own task and restarts it if it panics.
+async fn supervise(
+    chain: ChainInfo,
+    state: Arc<RwLock<State>>,
+    notifier: Notifier,
+    rejected: RejectedLogs,
+    operator: Option<ChatId>,
+) {
+    loop {
+        let task = {
+            let chain = chain.clone();
+            let state = state.clone();
+            let notifier = notifier.clone();
+            let rejected = rejected.clone();
+            tokio::spawn(async move {
+                chain_listener::listener(&chain, state, notifier, rejected, operator).await;
+            })
+        };
+        match task.await {
+            Ok(()) => return,
+            Err(e) => {
+                chain_listener::alert(
+                    notifier.bot(),
+                    operator,
+                    format!("{} listener crashed: {}, restarting.", chain.name, e),
+                )
+                .await;
+                sleep(RESTART_DELAY).await;
+            }
+        }
+    }
+}
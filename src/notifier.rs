@@ -0,0 +1,437 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use teloxide::{
+    prelude::*,
+    types::{MessageId, ParseMode},
+    ApiError, RequestError,
+};
+use tokio::{
+    sync::{mpsc, RwLock, Semaphore},
+    time::{sleep_until, Instant},
+};
+
+use crate::{render::Format, state::State};
+
+/// Telegram accepts about 30 messages per second overall,
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(35);
+/// and about one per second in the same chat.
+const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Attempts made before a transient error or rate limiting is considered
+/// permanent.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Requests to Telegram made at the same time. Notifications waiting for their
+/// slot hold none.
+const MAX_IN_FLIGHT: usize = 64;
+/// Notifications taken off the queue, waiting for their slot or being
+/// delivered. Past that many, [`Notifier::send`] fills the queue; a chat
+/// flooded with more notifications than this delays the other chats.
+const MAX_PENDING: usize = 256;
+/// Permanent failures kept for /status.
+const RECENT_FAILURES: usize = 10;
+
+/// A message waiting to be delivered by the [`Notifier`].
+pub struct Notification {
+    chat_id: ChatId,
+    text: String,
+    parse_mode: Option<ParseMode>,
+    reply_to: Option<MessageId>,
+    edit: Option<MessageId>,
+    on_sent: Option<Box<dyn FnOnce(MessageId) + Send>>,
+}
+
+impl Notification {
+    pub fn new(chat_id: ChatId, text: String) -> Self {
+        Self {
+            chat_id,
+            text,
+            parse_mode: None,
+            reply_to: None,
+            edit: None,
+            on_sent: None,
+        }
+    }
+
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = Some(parse_mode);
+        self
+    }
+
+    pub fn reply_to(mut self, message_id: MessageId) -> Self {
+        self.reply_to = Some(message_id);
+        self
+    }
+
+    /// Replaces the text of a message sent before instead of sending a new one.
+    pub fn edit(mut self, message_id: MessageId) -> Self {
+        self.edit = Some(message_id);
+        self
+    }
+
+    /// Called with the id of the message once it is delivered.
+    pub fn on_sent(mut self, on_sent: impl FnOnce(MessageId) + Send + 'static) -> Self {
+        self.on_sent = Some(Box::new(on_sent));
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    in_flight: AtomicUsize,
+    delivered: AtomicUsize,
+    failed: AtomicUsize,
+    recent_failures: Mutex<VecDeque<(ChatId, String)>>,
+}
+
+/// Snapshot of the [`Notifier`] counters.
+#[derive(Debug)]
+pub struct NotifierStatus {
+    pub queued: usize,
+    pub in_flight: usize,
+    pub delivered: usize,
+    pub failed: usize,
+    pub recent_failures: Vec<(ChatId, String)>,
+}
+
+/// Bounded queue delivering notifications within Telegram's rate limits,
+/// retrying transient errors. Chats the bot can no longer write to are
+/// deactivated in `State`.
+#[derive(Clone)]
+pub struct Notifier {
+    bot: Bot,
+    format: Format,
+    sender: mpsc::Sender<Notification>,
+    stats: Arc<Stats>,
+}
+
+impl Notifier {
+    /// Spawns the dispatcher task. Once `capacity` notifications are waiting,
+    /// besides the ones being delivered, [`Notifier::send`] waits for room in
+    /// the queue.
+    pub fn spawn(bot: Bot, state: Arc<RwLock<State>>, format: Format, capacity: usize) -> Self {
+        Self::spawn_bounded(bot, state, format, capacity, MAX_PENDING)
+    }
+
+    fn spawn_bounded(
+        bot: Bot,
+        state: Arc<RwLock<State>>,
+        format: Format,
+        capacity: usize,
+        max_pending: usize,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let stats = Arc::new(Stats::default());
+        tokio::spawn(dispatch(
+            bot.clone(),
+            state,
+            receiver,
+            max_pending,
+            stats.clone(),
+        ));
+        Self {
+            bot,
+            format,
+            sender,
+            stats,
+        }
+    }
+
+    pub fn bot(&self) -> &Bot {
+        &self.bot
+    }
+
+    /// Markup notifications are rendered with.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub async fn send(&self, notification: Notification) {
+        if self.sender.send(notification).await.is_err() {
+            log::error!("notification dropped, dispatcher is gone");
+        }
+    }
+
+    pub fn status(&self) -> NotifierStatus {
+        NotifierStatus {
+            queued: self.sender.max_capacity() - self.sender.capacity(),
+            in_flight: self.stats.in_flight.load(Ordering::Relaxed),
+            delivered: self.stats.delivered.load(Ordering::Relaxed),
+            failed: self.stats.failed.load(Ordering::Relaxed),
+            recent_failures: self
+                .stats
+                .recent_failures
+                .lock()
+                .expect("lock is never poisoned")
+                .iter()
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Hands out send slots so that no more than one message per `GLOBAL_INTERVAL`
+/// and one message per `CHAT_INTERVAL` in the same chat are sent.
+#[derive(Debug, Default)]
+struct RateLimiter {
+    next_global: Option<Instant>,
+    next_per_chat: HashMap<ChatId, Instant>,
+}
+
+impl RateLimiter {
+    /// Reserves the earliest slot at or after `not_before` for a message to `chat`.
+    fn reserve(&mut self, chat_id: ChatId, not_before: Instant) -> Instant {
+        let mut slot = not_before;
+        if let Some(next) = self.next_global {
+            slot = slot.max(next);
+        }
+        if let Some(next) = self.next_per_chat.get(&chat_id) {
+            slot = slot.max(*next);
+        }
+        self.next_global = Some(slot + GLOBAL_INTERVAL);
+        if self.next_per_chat.len() > 1_000 {
+            self.next_per_chat.retain(|_, next| *next > not_before);
+        }
+        self.next_per_chat.insert(chat_id, slot + CHAT_INTERVAL);
+        slot
+    }
+
+    /// Nothing is sent before `until`, Telegram asked to slow down.
+    fn pause(&mut self, until: Instant) {
+        self.next_global = Some(self.next_global.map_or(until, |next| next.max(until)));
+    }
+}
+
+async fn dispatch(
+    bot: Bot,
+    state: Arc<RwLock<State>>,
+    mut receiver: mpsc::Receiver<Notification>,
+    max_pending: usize,
+    stats: Arc<Stats>,
+) {
+    let limiter = Arc::new(Mutex::new(RateLimiter::default()));
+    let permits = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let pending = Arc::new(Semaphore::new(max_pending));
+
+    loop {
+        // Taken before receiving, the queue fills up and slows down the
+        // senders while too many notifications wait for their slot.
+        let pending = pending
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let Some(notification) = receiver.recv().await else {
+            return;
+        };
+        let slot = limiter
+            .lock()
+            .expect("lock is never poisoned")
+            .reserve(notification.chat_id, Instant::now());
+        stats.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        let bot = bot.clone();
+        let state = state.clone();
+        let limiter = limiter.clone();
+        let permits = permits.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            deliver(&bot, &state, notification, slot, &limiter, &permits, &stats).await;
+            stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+            drop(pending);
+        });
+    }
+}
+
+async fn deliver(
+    bot: &Bot,
+    state: &RwLock<State>,
+    mut notification: Notification,
+    mut slot: Instant,
+    limiter: &Mutex<RateLimiter>,
+    permits: &Semaphore,
+    stats: &Stats,
+) {
+    let chat_id = notification.chat_id;
+    let mut attempts = 0;
+    loop {
+        // Waiting for the slot first, a busy chat must not hold up the others.
+        sleep_until(slot).await;
+        let permit = permits.acquire().await.expect("semaphore is never closed");
+        let result = match notification.edit {
+            Some(message_id) => {
+                let mut request =
+                    bot.edit_message_text(chat_id, message_id, notification.text.clone());
+                if let Some(parse_mode) = notification.parse_mode {
+                    request = request.parse_mode(parse_mode);
+                }
+                request.await
+            }
+            None => {
+                let mut request = bot.send_message(chat_id, notification.text.clone());
+                if let Some(parse_mode) = notification.parse_mode {
+                    request = request.parse_mode(parse_mode);
+                }
+                if let Some(message_id) = notification.reply_to {
+                    request = request.reply_to_message_id(message_id);
+                }
+                request.await
+            }
+        };
+        drop(permit);
+
+        let error = match result {
+            Ok(message) => {
+                stats.delivered.fetch_add(1, Ordering::Relaxed);
+                if let Some(on_sent) = notification.on_sent.take() {
+                    on_sent(message.id);
+                }
+                return;
+            }
+            Err(e) => e,
+        };
+
+        attempts += 1;
+        let delay = match error {
+            RequestError::RetryAfter(delay) => {
+                log::warn!("rate limited by telegram for {:?}", delay);
+                limiter
+                    .lock()
+                    .expect("lock is never poisoned")
+                    .pause(Instant::now() + delay);
+                (attempts < MAX_ATTEMPTS).then_some(delay)
+            }
+            _ => (is_transient(&error) && attempts < MAX_ATTEMPTS)
+                .then(|| RETRY_DELAY * 2u32.pow(attempts - 1)),
+        };
+        if let Some(delay) = delay {
+            log::warn!(
+                "unable to notify {}: {}, retrying in {:?}",
+                chat_id,
+                error,
+                delay
+            );
+            slot = limiter
+                .lock()
+                .expect("lock is never poisoned")
+                .reserve(chat_id, Instant::now() + delay);
+            continue;
+        }
+
+        log::error!("unable to notify {}: {}", chat_id, error);
+        stats.failed.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut recent_failures = stats
+                .recent_failures
+                .lock()
+                .expect("lock is never poisoned");
+            if recent_failures.len() == RECENT_FAILURES {
+                recent_failures.pop_front();
+            }
+            recent_failures.push_back((chat_id, error.to_string()));
+        }
+        if is_chat_gone(&error) {
+            match state.write().await.set_chat_active(chat_id, false) {
+                Ok(true) => log::info!("deactivated subscriptions of {}", chat_id),
+                Ok(false) => {}
+                Err(e) => log::warn!("unable to deactivate {}: {}", chat_id, e),
+            }
+        }
+        return;
+    }
+}
+
+/// Errors worth retrying, anything reported by the Telegram API itself is permanent.
+fn is_transient(error: &RequestError) -> bool {
+    matches!(
+        error,
+        RequestError::Network(_) | RequestError::Io(_) | RequestError::InvalidJson { .. }
+    )
+}
+
+/// The bot was blocked, removed or the chat no longer exists, later messages
+/// would fail the same way until the user talks to the bot again.
+fn is_chat_gone(error: &RequestError) -> bool {
+    matches!(
+        error,
+        RequestError::Api(
+            ApiError::BotBlocked
+                | ApiError::ChatNotFound
+                | ApiError::UserDeactivated
+                | ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup
+                | ApiError::CantInitiateConversation
+                | ApiError::GroupDeactivated
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_rate_limiter() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        let (alice, bob) = (ChatId(1), ChatId(2));
+
+        assert_eq!(limiter.reserve(alice, now), now);
+        assert_eq!(limiter.reserve(bob, now), now + GLOBAL_INTERVAL);
+        assert_eq!(limiter.reserve(alice, now), now + CHAT_INTERVAL);
+
+        limiter.pause(now + Duration::from_secs(5));
+        assert_eq!(limiter.reserve(bob, now), now + Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_when_full() {
+        // Nothing listens there, deliveries keep failing and being retried.
+        let bot = Bot::new("0:token").set_api_url("http://127.0.0.1:1".parse().unwrap());
+        let state = Arc::new(RwLock::new(State::new(Box::new(MemoryStore::new()))));
+        let notifier = Notifier::spawn_bounded(bot, state, Format::Html, 1, 1);
+        let notification = || Notification::new(ChatId(1), "transfer".to_string());
+
+        // One being delivered and one in the queue.
+        notifier.send(notification()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        notifier.send(notification()).await;
+        assert_eq!(notifier.status().in_flight, 1);
+        assert_eq!(notifier.status().queued, 1);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), notifier.send(notification()))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&RequestError::Io(std::io::Error::other(
+            "reset"
+        ))));
+        assert!(!is_transient(&RequestError::Api(ApiError::BotBlocked)));
+        assert!(!is_transient(&RequestError::RetryAfter(
+            Duration::from_secs(1)
+        )));
+    }
+
+    #[test]
+    fn test_is_chat_gone() {
+        assert!(is_chat_gone(&RequestError::Api(ApiError::BotBlocked)));
+        assert!(is_chat_gone(&RequestError::Api(ApiError::ChatNotFound)));
+        assert!(!is_chat_gone(&RequestError::Api(
+            ApiError::MessageTextIsEmpty
+        )));
+        assert!(!is_chat_gone(&RequestError::Io(std::io::Error::other(
+            "reset"
+        ))));
+    }
+}
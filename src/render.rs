@@ -0,0 +1,817 @@
+use ethers::{
+    types::{Address, U256},
+    utils::to_checksum,
+};
+use std::ops::Range;
+use teloxide::types::ParseMode;
+
+use crate::state::{Direction, SubKind, Subscription};
+
+/// Telegram rejects longer messages, counted in UTF-16 code units.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Telegram markup used for notifications. Every interpolated value is escaped
+/// for it, token names and symbols come from arbitrary contracts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    MarkdownV2,
+    Html,
+}
+
+impl Format {
+    pub fn parse_mode(self) -> ParseMode {
+        match self {
+            Format::MarkdownV2 => ParseMode::MarkdownV2,
+            Format::Html => ParseMode::Html,
+        }
+    }
+
+    pub fn escape(self, text: &str) -> String {
+        match self {
+            Format::MarkdownV2 => escape_markdown(text, "\\_*[]()~`>#+-=|{}.!"),
+            Format::Html => text
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;"),
+        }
+    }
+
+    pub fn link(self, text: &str, url: &str) -> String {
+        match self {
+            Format::MarkdownV2 => {
+                format!("[{}]({})", self.escape(text), escape_markdown(url, "\\)"))
+            }
+            Format::Html => format!("<a href=\"{}\">{}</a>", self.escape(url), self.escape(text)),
+        }
+    }
+}
+
+fn escape_markdown(text: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Token ids listed in a single notification, ERC1155 batches can be long.
+const MAX_LISTED_IDS: usize = 10;
+
+/// What a notified transfer moved.
+#[derive(Debug, Clone)]
+pub enum Amount {
+    /// Formatted amount of a fungible token.
+    Tokens(String),
+    /// NFTs moved, ERC721 transfers move a single one.
+    Ids(Vec<TokenId>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenId {
+    pub id: U256,
+    /// Copies moved, None for ERC721 tokens which are unique.
+    pub amount: Option<U256>,
+    pub on_scanner: String,
+}
+
+/// Where a transfer first seen in the mempool stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingStatus {
+    Pending,
+    /// Included in the given block.
+    Mined(u64),
+    /// Included in the given block, but reverted.
+    Failed(u64),
+    /// Gone from the mempool without being included.
+    Dropped,
+    /// Another transaction of the sender took its nonce.
+    Replaced,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferEvent {
+    /// From the point of view of the watched address, None when notified to
+    /// token-wide subscriptions.
+    pub direction: Option<Direction>,
+    pub chain_name: String,
+    pub tx_on_scanner: String,
+    pub token_on_scanner: String,
+    pub name: String,
+    pub from: Address,
+    pub sender_on_scanner: String,
+    pub to: Address,
+    pub receiver_on_scanner: String,
+    pub amount: Amount,
+}
+
+impl TransferEvent {
+    pub fn render(&self, format: Format) -> String {
+        let (token, amount) = match &self.amount {
+            Amount::Tokens(amount) => ("Token", format!("Amount: {}", format.escape(amount))),
+            Amount::Ids(ids) => {
+                let mut listed: Vec<String> = ids
+                    .iter()
+                    .take(MAX_LISTED_IDS)
+                    .map(|token_id| {
+                        let link = format.link(&token_id.id.to_string(), &token_id.on_scanner);
+                        match token_id.amount {
+                            Some(amount) => format!("{} × {}", link, amount),
+                            None => link,
+                        }
+                    })
+                    .collect();
+                if ids.len() > MAX_LISTED_IDS {
+                    listed.push(format!("and {} more", ids.len() - MAX_LISTED_IDS));
+                }
+                let label = if ids.len() == 1 {
+                    "Token id"
+                } else {
+                    "Token ids"
+                };
+                ("Collection", format!("{}: {}", label, listed.join(", ")))
+            }
+        };
+        [
+            format!(
+                "Tokens {} on {}",
+                match self.direction {
+                    Some(Direction::In) => "received",
+                    Some(Direction::Out) => "sent",
+                    Some(Direction::Both) => "transfered between your watched addresses",
+                    None => "transfered above your minimum",
+                },
+                format.escape(&self.chain_name)
+            ),
+            format!(
+                "{}: {}",
+                token,
+                format.link(&self.name, &self.token_on_scanner)
+            ),
+            format!(
+                "From: {}",
+                format.link(&format!("{:#x}", self.from), &self.sender_on_scanner)
+            ),
+            format!(
+                "To: {}",
+                format.link(&format!("{:#x}", self.to), &self.receiver_on_scanner)
+            ),
+            amount,
+            format!(
+                "View tx on {}",
+                format.link("explorer", &self.tx_on_scanner)
+            ),
+        ]
+        .join("\n")
+    }
+
+    /// Notification of a transfer not mined yet, edited as its status changes.
+    pub fn render_pending(&self, format: Format, status: PendingStatus) -> String {
+        let status = match status {
+            PendingStatus::Pending => "Pending, not mined yet".to_string(),
+            PendingStatus::Mined(block) => format!("Mined in block {}", block),
+            PendingStatus::Failed(block) => format!("Failed in block {}", block),
+            PendingStatus::Dropped => "Dropped from the mempool".to_string(),
+            PendingStatus::Replaced => "Replaced by another transaction".to_string(),
+        };
+        format!("{}\n{}", format.escape(&status), self.render(format))
+    }
+}
+
+/// Allowance left to the spender by an approval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Allowance {
+    /// Formatted amount.
+    Limited(String),
+    Unlimited,
+    Revoked,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApprovalEvent {
+    pub chain_name: String,
+    pub tx_on_scanner: String,
+    pub token_on_scanner: String,
+    pub name: String,
+    pub owner: Address,
+    pub owner_on_scanner: String,
+    pub spender: Address,
+    pub spender_on_scanner: String,
+    /// Name given to the spender by the chain config, None flags it as unknown.
+    pub spender_label: Option<String>,
+    pub allowance: Allowance,
+}
+
+impl ApprovalEvent {
+    pub fn render(&self, format: Format) -> String {
+        let header = match self.allowance {
+            Allowance::Limited(_) => "Allowance granted",
+            Allowance::Unlimited => "Unlimited allowance granted",
+            Allowance::Revoked => "Allowance revoked",
+        };
+        let unknown = if self.spender_label.is_none() && self.allowance != Allowance::Revoked {
+            " to an unknown spender"
+        } else {
+            ""
+        };
+        let allowance = match &self.allowance {
+            Allowance::Limited(amount) => format.escape(amount),
+            Allowance::Unlimited => "unlimited".to_string(),
+            Allowance::Revoked => "0".to_string(),
+        };
+        [
+            format!(
+                "{}{} on {}",
+                header,
+                unknown,
+                format.escape(&self.chain_name)
+            ),
+            format!("Token: {}", format.link(&self.name, &self.token_on_scanner)),
+            format!(
+                "Owner: {}",
+                format.link(&format!("{:#x}", self.owner), &self.owner_on_scanner)
+            ),
+            format!(
+                "Spender: {} {}",
+                format.link(&format!("{:#x}", self.spender), &self.spender_on_scanner),
+                format.escape(&format!(
+                    "({})",
+                    self.spender_label.as_deref().unwrap_or("unknown")
+                ))
+            ),
+            format!("Allowance: {}", allowance),
+            format!(
+                "View tx on {}",
+                format.link("explorer", &self.tx_on_scanner)
+            ),
+        ]
+        .join("\n")
+    }
+}
+
+/// Longer parameters of custom events are cut, they may hold arbitrary bytes.
+const MAX_PARAM_LEN: usize = 256;
+
+/// Decoded parameter of a custom event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamValue {
+    /// Linked to its page on the explorer.
+    Address(Address, String),
+    Text(String),
+}
+
+/// Custom event emitted by a watched contract.
+#[derive(Debug, Clone)]
+pub struct ContractEvent {
+    pub chain_name: String,
+    pub tx_on_scanner: String,
+    pub name: String,
+    pub contract: Address,
+    pub contract_on_scanner: String,
+    /// Name given to the contract by the user.
+    pub label: Option<String>,
+    pub params: Vec<(String, ParamValue)>,
+}
+
+impl ContractEvent {
+    pub fn render(&self, format: Format) -> String {
+        let mut contract = format.link(&format!("{:#x}", self.contract), &self.contract_on_scanner);
+        if let Some(label) = &self.label {
+            contract.push_str(&format.escape(&format!(" ({})", label)));
+        }
+        let mut lines = vec![
+            format!(
+                "{} emitted on {}",
+                format.escape(&self.name),
+                format.escape(&self.chain_name)
+            ),
+            format!("Contract: {}", contract),
+        ];
+        for (name, value) in &self.params {
+            let value = match value {
+                ParamValue::Address(address, url) => format.link(&format!("{:#x}", address), url),
+                ParamValue::Text(text) if text.chars().count() > MAX_PARAM_LEN => {
+                    let cut: String = text.chars().take(MAX_PARAM_LEN).collect();
+                    format.escape(&format!("{}…", cut))
+                }
+                ParamValue::Text(text) => format.escape(text),
+            };
+            lines.push(format!("{}: {}", format.escape(name), value));
+        }
+        lines.push(format!(
+            "View tx on {}",
+            format.link("explorer", &self.tx_on_scanner)
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Plain text description of a subscription, as listed by /subs.
+/// `token` is the symbol and decimals, if known.
+pub fn subscription(sub: &Subscription, chain_name: &str, token: Option<(&str, u8)>) -> String {
+    if sub.kind == SubKind::Events {
+        let mut text = format!(
+            "#{} Custom event on {}\nContract: {}",
+            sub.id,
+            chain_name,
+            sub.token_address
+                .map_or("unknown".to_string(), |address| to_checksum(&address, None))
+        );
+        if let Some(label) = &sub.label {
+            text.push_str(&format!(" ({})", label));
+        }
+        text.push_str(&format!(
+            "\nEvent: {}",
+            sub.event
+                .as_ref()
+                .map_or("unknown".to_string(), |event| event.declaration())
+        ));
+        return text;
+    }
+    let mut text = format!(
+        "#{} {} on {}\nAddress: {}",
+        sub.id,
+        match (sub.token_address, token) {
+            (None, _) => "Any token",
+            (Some(_), Some((symbol, _))) => symbol,
+            (Some(_), None) => "Unknown token",
+        },
+        chain_name,
+        sub.token_sender_receiver
+            .map_or("any".to_string(), |address| to_checksum(&address, None))
+    );
+    if let Some(label) = &sub.label {
+        text.push_str(&format!(" ({})", label));
+    }
+    if sub.kind == SubKind::Approvals {
+        text.push_str("\nApprovals granted by the address");
+        return text;
+    }
+    match sub.direction {
+        Direction::In => text.push_str("\nDirection: received only"),
+        Direction::Out => text.push_str("\nDirection: sent only"),
+        Direction::Both => {}
+    }
+    if let Some(min_amount) = sub.min_amount {
+        let min_amount = match token {
+            Some((symbol, decimals)) => format!(
+                "{} {}",
+                trim_amount(&format_amount(min_amount, decimals)),
+                symbol
+            ),
+            None => format!("{} units", min_amount),
+        };
+        text.push_str(&format!("\nMinimum: {}", min_amount));
+    }
+    if let Some(filter) = &sub.filter {
+        text.push_str(&format!("\nFilter: {}", filter));
+    }
+    text
+}
+
+/// Splits `entries` into pages of at most `per_page` entries, keeping each page
+/// within `max_len` once joined by blank lines. Returns the entries of every page.
+pub fn paginate(entries: &[String], per_page: usize, max_len: usize) -> Vec<Range<usize>> {
+    let mut pages = Vec::new();
+    let mut start = 0;
+    let mut len = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let entry_len = entry.encode_utf16().count() + 2;
+        if i > start && (i - start == per_page || len + entry_len > max_len) {
+            pages.push(start..i);
+            start = i;
+            len = 0;
+        }
+        len += entry_len;
+    }
+    if start < entries.len() {
+        pages.push(start..entries.len());
+    }
+    pages
+}
+
+pub fn format_amount(amount: U256, decimals: u8) -> String {
+    if decimals == 0 {
+        return amount.to_string();
+    }
+    let units = decimals as usize;
+    let exp10 = U256::exp10(units);
+
+    let integer = amount / exp10;
+    let decimals = (amount % exp10).to_string();
+
+    format!("{integer}.{decimals:0>units$}")
+}
+
+/// Drops the trailing zeros of a formatted amount, "1.500" becomes "1.5".
+fn trim_amount(amount: &str) -> &str {
+    if !amount.contains('.') {
+        return amount;
+    }
+    amount.trim_end_matches('0').trim_end_matches('.')
+}
+
+/// Inverse of `format_amount`, None unless `text` is a non negative number
+/// with at most `decimals` decimal places.
+pub fn parse_amount(text: &str, decimals: u8) -> Option<U256> {
+    let (integer, fraction) = text.split_once('.').unwrap_or((text, ""));
+    let is_number = |digits: &str| digits.chars().all(|c| c.is_ascii_digit());
+    if (integer.is_empty() && fraction.is_empty())
+        || !is_number(integer)
+        || !is_number(fraction)
+        || fraction.len() > decimals as usize
+    {
+        return None;
+    }
+    let unit = U256::from(10).checked_pow(decimals.into())?;
+    let integer = match integer {
+        "" => U256::zero(),
+        integer => U256::from_dec_str(integer).ok()?,
+    };
+    let fraction = match fraction {
+        "" => U256::zero(),
+        fraction => {
+            let scale = U256::from(10).pow((decimals as usize - fraction.len()).into());
+            U256::from_dec_str(fraction).ok()? * scale
+        }
+    };
+    integer.checked_mul(unit)?.checked_add(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::custom_event::CustomEvent;
+    use insta::assert_snapshot;
+
+    fn event(name: &str) -> TransferEvent {
+        TransferEvent {
+            direction: Some(Direction::In),
+            chain_name: "ETH Sepolia".to_string(),
+            tx_on_scanner: "https://sepolia.etherscan.io/tx/0x01".to_string(),
+            token_on_scanner: "https://sepolia.etherscan.io/address/0x02".to_string(),
+            name: name.to_string(),
+            from: Address::from_low_u64_be(3),
+            sender_on_scanner: "https://sepolia.etherscan.io/address/0x03".to_string(),
+            to: Address::from_low_u64_be(4),
+            receiver_on_scanner: "https://scan.example/address/(0x04)".to_string(),
+            amount: Amount::Tokens(format_amount(U256::from(1_500_000), 6)),
+        }
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        assert_snapshot!(event("Wrapped_Ether-v2.0 (WETH)!").render(Format::MarkdownV2), @r"
+        Tokens received on ETH Sepolia
+        Token: [Wrapped\_Ether\-v2\.0 \(WETH\)\!](https://sepolia.etherscan.io/address/0x02)
+        From: [0x0000000000000000000000000000000000000003](https://sepolia.etherscan.io/address/0x03)
+        To: [0x0000000000000000000000000000000000000004](https://scan.example/address/(0x04\))
+        Amount: 1\.500000
+        View tx on [explorer](https://sepolia.etherscan.io/tx/0x01)
+        ");
+    }
+
+    #[test]
+    fn test_render_html() {
+        let event = TransferEvent {
+            direction: Some(Direction::Out),
+            ..event("<b>Fake</b> & \"Co\"")
+        };
+        assert_snapshot!(event.render(Format::Html), @r#"
+        Tokens sent on ETH Sepolia
+        Token: <a href="https://sepolia.etherscan.io/address/0x02">&lt;b&gt;Fake&lt;/b&gt; &amp; &quot;Co&quot;</a>
+        From: <a href="https://sepolia.etherscan.io/address/0x03">0x0000000000000000000000000000000000000003</a>
+        To: <a href="https://scan.example/address/(0x04)">0x0000000000000000000000000000000000000004</a>
+        Amount: 1.500000
+        View tx on <a href="https://sepolia.etherscan.io/tx/0x01">explorer</a>
+        "#);
+    }
+
+    #[test]
+    fn test_render_both_watched() {
+        let event = TransferEvent {
+            direction: Some(Direction::Both),
+            ..event("Token")
+        };
+        let rendered = event.render(Format::MarkdownV2);
+        assert_snapshot!(rendered.lines().next().unwrap(), @"Tokens transfered between your watched addresses on ETH Sepolia");
+    }
+
+    #[test]
+    fn test_render_token_wide() {
+        let event = TransferEvent {
+            direction: None,
+            ..event("Token")
+        };
+        let rendered = event.render(Format::MarkdownV2);
+        assert_snapshot!(rendered.lines().next().unwrap(), @"Tokens transfered above your minimum on ETH Sepolia");
+    }
+
+    #[test]
+    fn test_render_pending() {
+        let event = event("Token");
+        assert_snapshot!(event.render_pending(Format::MarkdownV2, PendingStatus::Pending), @r"
+        Pending, not mined yet
+        Tokens received on ETH Sepolia
+        Token: [Token](https://sepolia.etherscan.io/address/0x02)
+        From: [0x0000000000000000000000000000000000000003](https://sepolia.etherscan.io/address/0x03)
+        To: [0x0000000000000000000000000000000000000004](https://scan.example/address/(0x04\))
+        Amount: 1\.500000
+        View tx on [explorer](https://sepolia.etherscan.io/tx/0x01)
+        ");
+        let statuses: Vec<String> = [
+            PendingStatus::Mined(12),
+            PendingStatus::Failed(12),
+            PendingStatus::Dropped,
+            PendingStatus::Replaced,
+        ]
+        .into_iter()
+        .map(|status| {
+            let rendered = event.render_pending(Format::Html, status);
+            rendered.lines().next().unwrap().to_string()
+        })
+        .collect();
+        assert_snapshot!(statuses.join("\n"), @r"
+        Mined in block 12
+        Failed in block 12
+        Dropped from the mempool
+        Replaced by another transaction
+        ");
+    }
+
+    fn approval(allowance: Allowance, spender_label: Option<&str>) -> ApprovalEvent {
+        ApprovalEvent {
+            chain_name: "ETH Sepolia".to_string(),
+            tx_on_scanner: "https://sepolia.etherscan.io/tx/0x01".to_string(),
+            token_on_scanner: "https://sepolia.etherscan.io/address/0x02".to_string(),
+            name: "USD Coin".to_string(),
+            owner: Address::from_low_u64_be(3),
+            owner_on_scanner: "https://sepolia.etherscan.io/address/0x03".to_string(),
+            spender: Address::from_low_u64_be(4),
+            spender_on_scanner: "https://sepolia.etherscan.io/address/0x04".to_string(),
+            spender_label: spender_label.map(str::to_string),
+            allowance,
+        }
+    }
+
+    #[test]
+    fn test_render_approval() {
+        let unlimited = approval(Allowance::Unlimited, None);
+        assert_snapshot!(unlimited.render(Format::MarkdownV2), @r"
+        Unlimited allowance granted to an unknown spender on ETH Sepolia
+        Token: [USD Coin](https://sepolia.etherscan.io/address/0x02)
+        Owner: [0x0000000000000000000000000000000000000003](https://sepolia.etherscan.io/address/0x03)
+        Spender: [0x0000000000000000000000000000000000000004](https://sepolia.etherscan.io/address/0x04) \(unknown\)
+        Allowance: unlimited
+        View tx on [explorer](https://sepolia.etherscan.io/tx/0x01)
+        ");
+        let limited = approval(Allowance::Limited("1.5".to_string()), Some("Router"));
+        let rendered = limited.render(Format::Html);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_snapshot!([lines[0], lines[3], lines[4]].join("\n"), @r#"
+        Allowance granted on ETH Sepolia
+        Spender: <a href="https://sepolia.etherscan.io/address/0x04">0x0000000000000000000000000000000000000004</a> (Router)
+        Allowance: 1.5
+        "#);
+        let revoked = approval(Allowance::Revoked, None);
+        let rendered = revoked.render(Format::Html);
+        assert_snapshot!(rendered.lines().next().unwrap(), @"Allowance revoked on ETH Sepolia");
+    }
+
+    #[test]
+    fn test_render_contract_event() {
+        let event = ContractEvent {
+            chain_name: "ETH Sepolia".to_string(),
+            tx_on_scanner: "https://sepolia.etherscan.io/tx/0x01".to_string(),
+            name: "AddedOwner".to_string(),
+            contract: Address::from_low_u64_be(2),
+            contract_on_scanner: "https://sepolia.etherscan.io/address/0x02".to_string(),
+            label: Some("Treasury Safe".to_string()),
+            params: vec![
+                (
+                    "owner".to_string(),
+                    ParamValue::Address(
+                        Address::from_low_u64_be(3),
+                        "https://sepolia.etherscan.io/address/0x03".to_string(),
+                    ),
+                ),
+                (
+                    "threshold_v2".to_string(),
+                    ParamValue::Text("2".to_string()),
+                ),
+            ],
+        };
+        assert_snapshot!(event.render(Format::MarkdownV2), @r"
+        AddedOwner emitted on ETH Sepolia
+        Contract: [0x0000000000000000000000000000000000000002](https://sepolia.etherscan.io/address/0x02) \(Treasury Safe\)
+        owner: [0x0000000000000000000000000000000000000003](https://sepolia.etherscan.io/address/0x03)
+        threshold\_v2: 2
+        View tx on [explorer](https://sepolia.etherscan.io/tx/0x01)
+        ");
+        let long = ContractEvent {
+            label: None,
+            params: vec![("data".to_string(), ParamValue::Text("<ab>".repeat(100)))],
+            ..event
+        };
+        let rendered = long.render(Format::Html);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_snapshot!(lines[1], @r#"Contract: <a href="https://sepolia.etherscan.io/address/0x02">0x0000000000000000000000000000000000000002</a>"#);
+        assert!(lines[2].ends_with("&lt;ab&gt;…"));
+        assert_eq!(lines[2].matches("&lt;ab&gt;").count(), MAX_PARAM_LEN / 4);
+    }
+
+    #[test]
+    fn test_render_nft() {
+        let token_id = |id: u64, amount: Option<u64>| TokenId {
+            id: U256::from(id),
+            amount: amount.map(U256::from),
+            on_scanner: format!("https://sepolia.etherscan.io/nft/0x02/{}", id),
+        };
+        let nft = TransferEvent {
+            amount: Amount::Ids(vec![token_id(42, None)]),
+            ..event("Punks")
+        };
+        assert_snapshot!(nft.render(Format::MarkdownV2), @r"
+        Tokens received on ETH Sepolia
+        Collection: [Punks](https://sepolia.etherscan.io/address/0x02)
+        From: [0x0000000000000000000000000000000000000003](https://sepolia.etherscan.io/address/0x03)
+        To: [0x0000000000000000000000000000000000000004](https://scan.example/address/(0x04\))
+        Token id: [42](https://sepolia.etherscan.io/nft/0x02/42)
+        View tx on [explorer](https://sepolia.etherscan.io/tx/0x01)
+        ");
+
+        let batch = TransferEvent {
+            amount: Amount::Ids((1..=12).map(|id| token_id(id, Some(id * 10))).collect()),
+            ..event("Items")
+        };
+        let rendered = batch.render(Format::Html);
+        assert_snapshot!(rendered.lines().nth(4).unwrap(), @r#"Token ids: <a href="https://sepolia.etherscan.io/nft/0x02/1">1</a> × 10, <a href="https://sepolia.etherscan.io/nft/0x02/2">2</a> × 20, <a href="https://sepolia.etherscan.io/nft/0x02/3">3</a> × 30, <a href="https://sepolia.etherscan.io/nft/0x02/4">4</a> × 40, <a href="https://sepolia.etherscan.io/nft/0x02/5">5</a> × 50, <a href="https://sepolia.etherscan.io/nft/0x02/6">6</a> × 60, <a href="https://sepolia.etherscan.io/nft/0x02/7">7</a> × 70, <a href="https://sepolia.etherscan.io/nft/0x02/8">8</a> × 80, <a href="https://sepolia.etherscan.io/nft/0x02/9">9</a> × 90, <a href="https://sepolia.etherscan.io/nft/0x02/10">10</a> × 100, and 2 more"#);
+    }
+
+    #[test]
+    fn test_escape_markdown() {
+        let names = [
+            r"back\slash",
+            "*[bold]*",
+            "`code` ~strike~ > quote",
+            "#tag +1 -1 =x |a| {b}",
+        ];
+        let escaped: Vec<String> = names
+            .iter()
+            .map(|name| Format::MarkdownV2.escape(name))
+            .collect();
+        assert_snapshot!(escaped.join("\n"), @r"
+        back\\slash
+        \*\[bold\]\*
+        \`code\` \~strike\~ \> quote
+        \#tag \+1 \-1 \=x \|a\| \{b\}
+        ");
+    }
+
+    #[test]
+    fn test_subscription() {
+        let sub = Subscription {
+            id: 7,
+            chain_id: 1,
+            token_address: Some(Address::from_low_u64_be(1)),
+            token_sender_receiver: Some(
+                "0xfb6916095ca1df60bb79ce92ce3ea74c37c5d359"
+                    .parse()
+                    .unwrap(),
+            ),
+            label: Some("treasury".to_string()),
+            min_amount: None,
+            direction: Direction::Both,
+            kind: SubKind::Transfers,
+            event: None,
+            filter: None,
+        };
+        assert_snapshot!(subscription(&sub, "ETH Sepolia", Some(("USDC", 6))), @r"
+        #7 USDC on ETH Sepolia
+        Address: 0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359 (treasury)
+        ");
+        let events = Subscription {
+            token_sender_receiver: None,
+            kind: SubKind::Events,
+            event: Some(CustomEvent::parse("AddedOwner(address indexed owner)").unwrap()),
+            ..sub.clone()
+        };
+        assert_snapshot!(subscription(&events, "ETH Sepolia", None), @r"
+        #7 Custom event on ETH Sepolia
+        Contract: 0x0000000000000000000000000000000000000001 (treasury)
+        Event: AddedOwner(address indexed owner)
+        ");
+        let approvals = Subscription {
+            token_address: None,
+            kind: SubKind::Approvals,
+            ..sub.clone()
+        };
+        assert_snapshot!(subscription(&approvals, "ETH Sepolia", None), @r"
+        #7 Any token on ETH Sepolia
+        Address: 0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359 (treasury)
+        Approvals granted by the address
+        ");
+        let sub = Subscription {
+            label: None,
+            min_amount: Some(U256::from(2_500_000)),
+            direction: Direction::Out,
+            ..sub
+        };
+        assert_snapshot!(subscription(&sub, "ETH Sepolia", Some(("USDC", 6))), @r"
+        #7 USDC on ETH Sepolia
+        Address: 0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359
+        Direction: sent only
+        Minimum: 2.5 USDC
+        ");
+        assert_snapshot!(subscription(&sub, "Local", None), @r"
+        #7 Unknown token on Local
+        Address: 0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359
+        Direction: sent only
+        Minimum: 2500000 units
+        ");
+        let sub = Subscription {
+            token_address: None,
+            min_amount: None,
+            direction: Direction::In,
+            filter: Some("amount > 10k and label != \"Kraken\"".parse().unwrap()),
+            ..sub
+        };
+        assert_snapshot!(subscription(&sub, "Local", None), @r#"
+        #7 Any token on Local
+        Address: 0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359
+        Direction: received only
+        Filter: amount > 10k and label != "Kraken"
+        "#);
+        let sub = Subscription {
+            token_address: Some(Address::from_low_u64_be(1)),
+            token_sender_receiver: None,
+            label: None,
+            min_amount: Some(U256::exp10(12)),
+            direction: Direction::Both,
+            filter: None,
+            ..sub
+        };
+        assert_snapshot!(subscription(&sub, "ETH Sepolia", Some(("USDC", 6))), @r"
+        #7 USDC on ETH Sepolia
+        Address: any
+        Minimum: 1000000 USDC
+        ");
+    }
+
+    #[test]
+    fn test_paginate() {
+        let entries: Vec<String> = (0..25).map(|i| format!("entry {}", i)).collect();
+        assert_eq!(
+            paginate(&entries, 10, MAX_MESSAGE_LEN),
+            vec![0..10, 10..20, 20..25]
+        );
+        assert!(paginate(&[], 10, MAX_MESSAGE_LEN).is_empty());
+
+        let long: Vec<String> = (0..5).map(|_| "x".repeat(1500)).collect();
+        assert_eq!(paginate(&long, 10, MAX_MESSAGE_LEN), vec![0..2, 2..4, 4..5]);
+        // Emojis take two UTF-16 code units.
+        let emojis: Vec<String> = (0..3).map(|_| "🦀".repeat(1500)).collect();
+        assert_eq!(
+            paginate(&emojis, 10, MAX_MESSAGE_LEN),
+            vec![0..1, 1..2, 2..3]
+        );
+    }
+
+    #[test]
+    fn test_format_amount() {
+        //131.55 ether
+        let amount = U256::from_dec_str("131550000000000000000").unwrap();
+        let formated = format_amount(amount, 18);
+        assert_eq!(formated, "131.550000000000000000".to_string());
+
+        //1.2
+        let amount = U256::from_dec_str("1200000").unwrap();
+        let formated = format_amount(amount, 6);
+        assert_eq!(formated, "1.200000".to_string());
+        assert_eq!(trim_amount(&formated), "1.2");
+        assert_eq!(format_amount(U256::from(5), 0), "5");
+        assert_eq!(trim_amount(&format_amount(U256::from(100), 0)), "100");
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("1.2", 6), Some(U256::from(1_200_000)));
+        assert_eq!(parse_amount("1000", 6), Some(U256::from(1_000_000_000)));
+        assert_eq!(parse_amount(".5", 1), Some(U256::from(5)));
+        assert_eq!(parse_amount("7", 0), Some(U256::from(7)));
+        assert_eq!(
+            parse_amount("131.55", 18),
+            U256::from_dec_str("131550000000000000000").ok()
+        );
+        for invalid in ["", ".", "-1", "1.2.3", "1e6", "abc", "1.0000001"] {
+            assert_eq!(parse_amount(invalid, 6), None, "{}", invalid);
+        }
+        // Would overflow a U256.
+        assert_eq!(parse_amount(&"9".repeat(70), 18), None);
+    }
+}
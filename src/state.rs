@@ -1,91 +1,200 @@
-use ethers::types::Address;
-use eyre::{eyre, Ok, Result};
-use lazy_static::lazy_static;
-use std::collections::{HashMap, HashSet};
+use ethers::types::{Address, H256, U256};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, str::FromStr};
 use teloxide::types::ChatId;
+use tokio::sync::watch;
 
-lazy_static! {
-    pub static ref CHAINS_INFO: HashMap<u32, ChainInfo> = {
-        let mut m = HashMap::new();
-        // m.insert(
-        //     0,
-        //     ChainInfo {
-        //         id: 0,
-        //         name: "Local",
-        //         scanner_url: "https://sepolia.etherscan.io/",
-        //         ws: "ws://localhost:8545",
-        //     },
-        // );
-
-        m.insert(
-            11155111,
-            ChainInfo {
-                id: 11155111,
-                name: "ETH Sepolia",
-                scanner_url: "https://sepolia.etherscan.io/",
-                ws: "wss://sepolia.gateway.tenderly.co",
-            },
-        );
-        m
-    };
-    pub static ref AVAILABLE_CHAINS: HashMap<&'static str, u32> = {
-        let mut m = HashMap::new();
-        // m.insert("Local", 0);
-        m.insert("ETH Sepolia", 11155111);
-        m
-    };
+use crate::{
+    custom_event::CustomEvent,
+    filter::TransferFilter,
+    store::{LogKey, SubscriptionStore},
+    transfer::NATIVE,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    /// Assigned by the store when the subscription is created, never reused.
+    pub id: u64,
+    pub chain_id: u32,
+    /// None matches transfers of any token.
+    pub token_address: Option<Address>,
+    /// None matches every transfer of the token above `min_amount`, which is
+    /// then required.
+    pub token_sender_receiver: Option<Address>,
+    /// Name given by the user to the watched address.
+    pub label: Option<String>,
+    /// Smaller transfers are not notified, in the token's smallest unit.
+    pub min_amount: Option<U256>,
+    pub direction: Direction,
+    pub kind: SubKind,
+    /// Event of `token_address` watched by custom event subscriptions.
+    pub event: Option<CustomEvent>,
+    /// Further condition transfers must meet to be notified.
+    pub filter: Option<TransferFilter>,
 }
 
-pub struct ChainInfo {
-    pub id: u32,
-    pub name: &'static str,
-    pub scanner_url: &'static str,
-    pub ws: &'static str,
+/// Transfers notified, from the point of view of the watched address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Direction {
+    /// Received by the watched address.
+    In,
+    /// Sent by the watched address.
+    Out,
+    #[default]
+    Both,
 }
 
-#[derive(Debug)]
-pub struct Subscription {
-    chain_id: u32,
-    token_address: Address,
-    token_sender_receiver: Address,
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+            Direction::Both => "both",
+        }
+    }
+
+    pub fn includes(&self, other: Direction) -> bool {
+        *self == Direction::Both || *self == other
+    }
 }
 
-#[derive(Debug)]
-pub struct State {
-    //chain Id -> token address -> user address -> subscribed users
-    pub subs: HashMap<u32, HashMap<Address, HashMap<Address, HashSet<ChatId>>>>,
-    pub user_subs: HashMap<ChatId, Vec<Subscription>>,
-    //chain Id -> token Address -> (name, symbol,decimals)
-    pub cached_token_metadata: HashMap<u32, HashMap<Address, (String, String, u8)>>,
+impl FromStr for Direction {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "in" => Ok(Direction::In),
+            "out" => Ok(Direction::Out),
+            "both" => Ok(Direction::Both),
+            _ => Err(eyre!("unknown direction {}", s)),
+        }
+    }
 }
 
-impl State {
-    pub fn new() -> Self {
-        let mut subs: HashMap<u32, HashMap<Address, HashMap<Address, HashSet<ChatId>>>> =
-            HashMap::new();
-        let mut cached_token_metadata: HashMap<u32, HashMap<Address, (String, String, u8)>> =
-            HashMap::new();
-        for id in CHAINS_INFO.keys() {
-            subs.insert(*id, HashMap::new());
-            cached_token_metadata.insert(*id, HashMap::new());
+/// Events notified by a subscription.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SubKind {
+    #[default]
+    Transfers,
+    /// ERC20 allowances granted by the watched address, the direction and
+    /// minimum amount don't apply.
+    Approvals,
+    /// Any event of a contract, given by the user. The contract is the token
+    /// address and no address is watched.
+    Events,
+}
+
+impl SubKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubKind::Transfers => "transfers",
+            SubKind::Approvals => "approvals",
+            SubKind::Events => "events",
         }
+    }
+}
+
+impl FromStr for SubKind {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "transfers" => Ok(SubKind::Transfers),
+            "approvals" => Ok(SubKind::Approvals),
+            "events" => Ok(SubKind::Events),
+            _ => Err(eyre!("unknown subscription kind {}", s)),
+        }
+    }
+}
+
+impl Subscription {
+    /// Whether both subscriptions watch the same address for the same token and
+    /// events, a chat keeps a single subscription per target.
+    pub fn same_target(&self, other: &Subscription) -> bool {
+        self.kind == other.kind
+            && self.chain_id == other.chain_id
+            && self.token_address == other.token_address
+            && self.token_sender_receiver == other.token_sender_receiver
+            && self.event == other.event
+    }
 
+    pub fn matches_amount(&self, amount: U256) -> bool {
+        self.min_amount
+            .is_none_or(|min_amount| amount >= min_amount)
+    }
+}
+
+/// Outcome of [`State::insert_sub`], with the id of the subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Saved {
+    Created(u64),
+    /// The chat was already subscribed to the same target, whose settings
+    /// were replaced.
+    Updated(u64),
+}
+
+/// Tokens and addresses with at least one subscriber on a chain.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WatchSet {
+    pub tokens: BTreeSet<Address>,
+    pub addresses: BTreeSet<Address>,
+    /// Addresses subscribed to transfers of any token.
+    pub any_token_addresses: BTreeSet<Address>,
+    /// Tokens subscribed to regardless of the addresses involved.
+    pub token_wide: BTreeSet<Address>,
+    /// Addresses whose approvals are watched.
+    pub approval_owners: BTreeSet<Address>,
+    /// Contracts emitting custom events,
+    pub event_contracts: BTreeSet<Address>,
+    /// and the topics of these events.
+    pub event_topics: BTreeSet<H256>,
+}
+
+impl WatchSet {
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+            && self.any_token_addresses.is_empty()
+            && self.token_wide.is_empty()
+            && self.approval_owners.is_empty()
+            && self.event_contracts.is_empty()
+    }
+
+    /// Whether blocks must be scanned for transfers of the native currency.
+    pub fn watches_native(&self) -> bool {
+        self.tokens.contains(&NATIVE) || self.token_wide.contains(&NATIVE)
+    }
+}
+
+pub struct State {
+    store: Box<dyn SubscriptionStore>,
+    // Notified whenever the set of matched subscriptions changes.
+    watch_changes: watch::Sender<()>,
+}
+
+impl State {
+    pub fn new(store: Box<dyn SubscriptionStore>) -> Self {
         Self {
-            subs,
-            user_subs: HashMap::new(),
-            cached_token_metadata,
+            store,
+            watch_changes: watch::channel(()).0,
         }
     }
 
+    pub fn get_watch_set(&self, chain_id: &u32) -> WatchSet {
+        self.store.get_watch_set(chain_id)
+    }
+
+    /// Receiver marked as changed every time subscriptions are added or removed.
+    pub fn watch_changes(&self) -> watch::Receiver<()> {
+        self.watch_changes.subscribe()
+    }
+
     pub fn get_token_metadata(
         &self,
         chain_id: &u32,
         token_address: &Address,
     ) -> Option<&(String, String, u8)> {
-        self.cached_token_metadata
-            .get(chain_id)
-            .expect("chain will exist")
-            .get(token_address)
+        self.store.get_token_metadata(chain_id, token_address)
     }
 
     pub fn insert_token_metadata(
@@ -95,128 +204,146 @@ impl State {
         token_name: String,
         token_symbol: String,
         decimals: u8,
-    ) {
-        self.cached_token_metadata
-            .get_mut(chain_id)
-            .expect("chain will exist")
-            .insert(token_address, (token_name, token_symbol, decimals));
-    }
-
-    pub fn get_user_subscriptions_formated(&self, user: &ChatId) -> Option<String> {
-        if let Some(subs) = self.user_subs.get(user) {
-            if subs.len() != 0 {
-                return Some(format!(
-                    "{:?}",
-                    subs.iter()
-                        .enumerate()
-                        .collect::<Vec<(usize, &Subscription)>>()
-                ));
-            }
-        }
-        None
-    }
-
-    pub fn remove_sub(&mut self, user: &ChatId, index: usize) -> Result<Subscription> {
-        if let Some(user_subs) = self.user_subs.get_mut(user) {
-            if index < user_subs.len() {
-                let subscription = user_subs.remove(index);
-                let subscribed_users = self
-                    .subs
-                    .get_mut(&subscription.chain_id)
-                    .expect("chain will exist")
-                    .get_mut(&subscription.token_address)
-                    .expect("token will exist")
-                    .get_mut(&subscription.token_sender_receiver)
-                    .expect("user will exist");
-                subscribed_users.remove(user);
-                Ok(subscription)
-            } else {
-                Err(eyre!("index out of bounds"))
-            }
-        } else {
-            Err(eyre!("No subs"))
-        }
+    ) -> Result<()> {
+        self.store.insert_token_metadata(
+            chain_id,
+            token_address,
+            (token_name, token_symbol, decimals),
+        )
     }
 
-    pub fn get_sub_users(
-        &self,
-        chain_id: &u32,
-        token_address: &Address,
-        token_sender_receiver: &Address,
-    ) -> Option<&HashSet<ChatId>> {
-        if let Some(tokens) = self.subs.get(chain_id) {
-            if let Some(addresses) = tokens.get(token_address) {
-                return addresses.get(token_sender_receiver);
-            }
-        }
-        None
+    pub fn get_user_subs(&self, user: &ChatId) -> &[Subscription] {
+        self.store
+            .get_user_subs(user)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
     }
 
-    pub fn insert_sub(
-        &mut self,
+    pub fn remove_sub(&mut self, user: &ChatId, id: u64) -> Result<Subscription> {
+        let subscription = self.store.remove_sub(user, id)?;
+        self.watch_changes.send_replace(());
+        Ok(subscription)
+    }
+
+    /// Subscriptions of active chats watching `token_sender_receiver` for
+    /// `token_address` or for any token, the native currency not being one.
+    pub fn get_matching_subs(
+        &self,
         chain_id: u32,
         token_address: Address,
         token_sender_receiver: Address,
-        user_id: ChatId,
-    ) {
-        let tokens = self.subs.get_mut(&chain_id).expect("chain will exist");
-
-        match tokens.get_mut(&token_address) {
-            Some(addresses) => match addresses.get_mut(&token_sender_receiver) {
-                Some(s) => {
-                    if !s.insert(user_id) {
-                        return;
-                    }
-                }
-                None => {
-                    let mut v = HashSet::new();
-                    v.insert(user_id);
-                    addresses.insert(token_sender_receiver, v);
-                }
-            },
-            None => {
-                tokens.insert(
-                    token_address,
-                    Self::init_user(token_sender_receiver, user_id),
-                );
-            }
-        }
-        self.add_user_sub(chain_id, token_address, token_sender_receiver, user_id);
+    ) -> impl Iterator<Item = &(ChatId, Subscription)> {
+        let token_subs =
+            self.store
+                .get_matching_subs(&chain_id, &Some(token_address), &token_sender_receiver);
+        let any_token_subs = if token_address == NATIVE {
+            &[]
+        } else {
+            self.store
+                .get_matching_subs(&chain_id, &None, &token_sender_receiver)
+        };
+        token_subs.iter().chain(any_token_subs)
     }
 
-    fn init_user(
-        token_sender_receiver: Address,
-        user_id: ChatId,
-    ) -> HashMap<Address, HashSet<ChatId>> {
-        let mut m = HashMap::new();
-        let mut v = HashSet::new();
-        v.insert(user_id);
-        m.insert(token_sender_receiver, v);
-        m
+    /// Subscriptions of active chats to every transfer of `token_address`.
+    pub fn get_token_subs(
+        &self,
+        chain_id: u32,
+        token_address: Address,
+    ) -> &[(ChatId, Subscription)] {
+        self.store.get_token_subs(&chain_id, &token_address)
     }
 
-    fn add_user_sub(
-        &mut self,
+    /// Approval subscriptions of active chats watching `owner` for `token_address`
+    /// or for any token.
+    pub fn get_approval_subs(
+        &self,
         chain_id: u32,
         token_address: Address,
-        token_sender_receiver: Address,
-        user_id: ChatId,
-    ) {
-        if let Some(subs) = self.user_subs.get_mut(&user_id) {
-            subs.push(Subscription {
-                chain_id,
-                token_address,
-                token_sender_receiver,
-            });
+        owner: Address,
+    ) -> impl Iterator<Item = &(ChatId, Subscription)> {
+        self.store
+            .get_approval_subs(&chain_id, &owner)
+            .iter()
+            .filter(move |(_, sub)| sub.token_address.is_none_or(|token| token == token_address))
+    }
+
+    /// Custom event subscriptions of active chats to `contract`.
+    pub fn get_event_subs(&self, chain_id: u32, contract: Address) -> &[(ChatId, Subscription)] {
+        self.store.get_event_subs(&chain_id, &contract)
+    }
+
+    /// Stores `sub`, or updates the subscription of the chat to the same
+    /// target. `sub.id` is ignored.
+    pub fn insert_sub(&mut self, user_id: ChatId, sub: Subscription) -> Result<Saved> {
+        if let Some(filter) = &sub.filter {
+            if sub.kind != SubKind::Transfers {
+                return Err(eyre!("only transfer subscriptions have a filter"));
+            }
+            if sub.token_sender_receiver.is_none() && filter.uses_counterparty() {
+                return Err(eyre!("there is no counterparty without a watched address"));
+            }
+        }
+        if sub.kind == SubKind::Events {
+            if sub.token_address.is_none() || sub.token_sender_receiver.is_some() {
+                return Err(eyre!("events are watched for a given contract"));
+            }
+            if sub.event.is_none() {
+                return Err(eyre!("an event is required"));
+            }
+        } else if sub.event.is_some() {
+            return Err(eyre!("only custom event subscriptions have an event"));
+        } else if sub.kind == SubKind::Approvals {
+            if sub.token_sender_receiver.is_none() {
+                return Err(eyre!("approvals are watched for a given address"));
+            }
+            if sub.token_address == Some(NATIVE) {
+                return Err(eyre!("the native currency has no approvals"));
+            }
+        } else if sub.token_sender_receiver.is_none() {
+            if sub.token_address.is_none() {
+                return Err(eyre!("a token is required to watch every address"));
+            }
+            if sub.min_amount.is_none() {
+                return Err(eyre!("a minimum amount is required to watch every address"));
+            }
+        }
+        let exists = self
+            .get_user_subs(&user_id)
+            .iter()
+            .any(|existing| existing.same_target(&sub));
+        let id = self.store.insert_sub(user_id, sub)?;
+        self.watch_changes.send_replace(());
+        Ok(if exists {
+            Saved::Updated(id)
         } else {
-            self.user_subs.insert(
-                user_id,
-                vec![Subscription {
-                    chain_id,
-                    token_address,
-                    token_sender_receiver,
-                }],
-            );
+            Saved::Created(id)
+        })
+    }
+
+    pub fn get_chain_cursor(&self, chain_id: &u32) -> Option<u64> {
+        self.store.get_chain_cursor(chain_id)
+    }
+
+    pub fn set_chain_cursor(&mut self, chain_id: &u32, block: u64) -> Result<()> {
+        self.store.set_chain_cursor(chain_id, block)
+    }
+
+    pub fn mark_notified(&mut self, chain_id: &u32, key: LogKey, block: u64) -> Result<bool> {
+        self.store.mark_notified(chain_id, key, block)
+    }
+
+    pub fn unmark_notified(&mut self, chain_id: &u32, key: &LogKey) -> Result<()> {
+        self.store.unmark_notified(chain_id, key)
+    }
+
+    /// Stops or resumes matching the subscriptions of `user`.
+    /// Returns false if the chat was already in that state.
+    pub fn set_chat_active(&mut self, user: ChatId, active: bool) -> Result<bool> {
+        let changed = self.store.set_chat_active(user, active)?;
+        if changed {
+            self.watch_changes.send_replace(());
         }
+        Ok(changed)
     }
 }
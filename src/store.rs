@@ -0,0 +1,1248 @@
+use ethers::types::{Address, H256, U256};
+use eyre::{eyre, Result};
+use rusqlite::{params, Connection};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+use teloxide::types::ChatId;
+
+use crate::{
+    custom_event::CustomEvent,
+    filter::TransferFilter,
+    state::{SubKind, Subscription, WatchSet},
+};
+
+/// Identifies a log by (transaction hash, log index).
+pub type LogKey = (H256, u64);
+
+/// Subscriptions matching a transfer, with the chat to notify.
+type Subscribers = Vec<(ChatId, Subscription)>;
+
+/// Backend responsible for keeping subscriptions, cached token metadata and the
+/// progress of every chain listener.
+pub trait SubscriptionStore: Send + Sync {
+    /// Adds `sub` for `user` under a new id, `sub.id` is ignored.
+    /// Subscribing twice to the same target keeps the existing id and replaces
+    /// its label, minimum amount, direction and filter.
+    fn insert_sub(&mut self, user: ChatId, sub: Subscription) -> Result<u64>;
+
+    /// Removes the subscription of `user` with the given id.
+    fn remove_sub(&mut self, user: &ChatId, id: u64) -> Result<Subscription>;
+
+    /// Subscriptions of active chats for exactly `token_address`, None being any token.
+    fn get_matching_subs(
+        &self,
+        chain_id: &u32,
+        token_address: &Option<Address>,
+        token_sender_receiver: &Address,
+    ) -> &[(ChatId, Subscription)];
+
+    /// Subscriptions of active chats to every transfer of `token_address`.
+    fn get_token_subs(&self, chain_id: &u32, token_address: &Address) -> &[(ChatId, Subscription)];
+
+    /// Approval subscriptions of active chats watching `owner`, for any token.
+    fn get_approval_subs(&self, chain_id: &u32, owner: &Address) -> &[(ChatId, Subscription)];
+
+    /// Custom event subscriptions of active chats to `contract`, for any event.
+    fn get_event_subs(&self, chain_id: &u32, contract: &Address) -> &[(ChatId, Subscription)];
+
+    fn get_user_subs(&self, user: &ChatId) -> Option<&Vec<Subscription>>;
+
+    fn get_watch_set(&self, chain_id: &u32) -> WatchSet;
+
+    fn get_token_metadata(
+        &self,
+        chain_id: &u32,
+        token_address: &Address,
+    ) -> Option<&(String, String, u8)>;
+
+    fn insert_token_metadata(
+        &mut self,
+        chain_id: &u32,
+        token_address: Address,
+        metadata: (String, String, u8),
+    ) -> Result<()>;
+
+    /// Last block whose logs were all processed.
+    fn get_chain_cursor(&self, chain_id: &u32) -> Option<u64>;
+
+    /// Moves the cursor forward, forgetting notified logs up to `block`.
+    fn set_chain_cursor(&mut self, chain_id: &u32, block: u64) -> Result<()>;
+
+    /// Records that users were notified about a log.
+    /// Returns false if it had already been recorded.
+    fn mark_notified(&mut self, chain_id: &u32, key: LogKey, block: u64) -> Result<bool>;
+
+    /// Forgets a notified log, used when it is reorged out of the chain.
+    fn unmark_notified(&mut self, chain_id: &u32, key: &LogKey) -> Result<()>;
+
+    /// Subscriptions of inactive chats are kept but no longer matched.
+    /// Returns false if the chat was already in that state.
+    fn set_chat_active(&mut self, user: ChatId, active: bool) -> Result<bool>;
+
+    fn is_chat_active(&self, user: &ChatId) -> bool;
+}
+
+/// Volatile store, everything is lost when the process exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    //chain Id -> token address, None for any token -> user address -> subscriptions of active chats
+    subs: HashMap<u32, HashMap<Option<Address>, HashMap<Address, Subscribers>>>,
+    //chain Id -> token address -> token-wide subscriptions of active chats, kept apart
+    //so matching a transfer stays a couple of lookups
+    token_subs: HashMap<u32, HashMap<Address, Subscribers>>,
+    //chain Id -> owner address -> approval subscriptions of active chats
+    approval_subs: HashMap<u32, HashMap<Address, Subscribers>>,
+    //chain Id -> contract address -> custom event subscriptions of active chats
+    event_subs: HashMap<u32, HashMap<Address, Subscribers>>,
+    user_subs: HashMap<ChatId, Vec<Subscription>>,
+    next_sub_id: u64,
+    //chain Id -> token Address -> (name, symbol,decimals)
+    cached_token_metadata: HashMap<u32, HashMap<Address, (String, String, u8)>>,
+    chain_cursors: HashMap<u32, u64>,
+    //chain Id -> notified log -> block number
+    notified_logs: HashMap<u32, HashMap<LogKey, u64>>,
+    inactive_chats: HashSet<ChatId>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `sub` keeping its id.
+    fn insert(&mut self, user: ChatId, sub: Subscription) {
+        self.next_sub_id = self.next_sub_id.max(sub.id + 1);
+        if self.is_chat_active(&user) {
+            self.index(user, &sub);
+        }
+        self.user_subs.entry(user).or_default().push(sub);
+    }
+
+    /// Subscription of `user` matching the same transfers as `sub`.
+    fn find(&self, user: &ChatId, sub: &Subscription) -> Option<&Subscription> {
+        self.user_subs
+            .get(user)?
+            .iter()
+            .find(|existing| existing.same_target(sub))
+    }
+
+    /// Makes `sub` match transfers for `user`.
+    fn index(&mut self, user: ChatId, sub: &Subscription) {
+        let subscribers = match (sub.kind, sub.token_address, sub.token_sender_receiver) {
+            (SubKind::Approvals, _, Some(owner)) => self
+                .approval_subs
+                .entry(sub.chain_id)
+                .or_default()
+                .entry(owner)
+                .or_default(),
+            (SubKind::Events, Some(contract), _) => self
+                .event_subs
+                .entry(sub.chain_id)
+                .or_default()
+                .entry(contract)
+                .or_default(),
+            (SubKind::Transfers, token_address, Some(address)) => self
+                .subs
+                .entry(sub.chain_id)
+                .or_default()
+                .entry(token_address)
+                .or_default()
+                .entry(address)
+                .or_default(),
+            (SubKind::Transfers, Some(token_address), None) => self
+                .token_subs
+                .entry(sub.chain_id)
+                .or_default()
+                .entry(token_address)
+                .or_default(),
+            // Rejected by `State::insert_sub`.
+            (SubKind::Transfers, None, None)
+            | (SubKind::Approvals, _, None)
+            | (SubKind::Events, None, _) => return,
+        };
+        subscribers.push((user, sub.clone()));
+    }
+
+    fn unindex(&mut self, user: &ChatId, sub: &Subscription) {
+        let is_other =
+            |(chat_id, indexed): &(ChatId, Subscription)| chat_id != user || indexed.id != sub.id;
+        match (sub.kind, sub.token_address, sub.token_sender_receiver) {
+            (SubKind::Approvals, _, Some(owner)) => {
+                if let Some(owners) = self.approval_subs.get_mut(&sub.chain_id) {
+                    if let Some(subscribed_users) = owners.get_mut(&owner) {
+                        subscribed_users.retain(is_other);
+                        if subscribed_users.is_empty() {
+                            owners.remove(&owner);
+                        }
+                    }
+                }
+            }
+            (SubKind::Events, Some(contract), _) => {
+                if let Some(contracts) = self.event_subs.get_mut(&sub.chain_id) {
+                    if let Some(subscribed_users) = contracts.get_mut(&contract) {
+                        subscribed_users.retain(is_other);
+                        if subscribed_users.is_empty() {
+                            contracts.remove(&contract);
+                        }
+                    }
+                }
+            }
+            (SubKind::Transfers, token_address, Some(address)) => {
+                if let Some(tokens) = self.subs.get_mut(&sub.chain_id) {
+                    if let Some(addresses) = tokens.get_mut(&token_address) {
+                        if let Some(subscribed_users) = addresses.get_mut(&address) {
+                            subscribed_users.retain(is_other);
+                            if subscribed_users.is_empty() {
+                                addresses.remove(&address);
+                            }
+                        }
+                        if addresses.is_empty() {
+                            tokens.remove(&token_address);
+                        }
+                    }
+                }
+            }
+            (SubKind::Transfers, Some(token_address), None) => {
+                if let Some(tokens) = self.token_subs.get_mut(&sub.chain_id) {
+                    if let Some(subscribed_users) = tokens.get_mut(&token_address) {
+                        subscribed_users.retain(is_other);
+                        if subscribed_users.is_empty() {
+                            tokens.remove(&token_address);
+                        }
+                    }
+                }
+            }
+            (SubKind::Transfers, None, None)
+            | (SubKind::Approvals, _, None)
+            | (SubKind::Events, None, _) => {}
+        }
+    }
+}
+
+impl SubscriptionStore for MemoryStore {
+    fn insert_sub(&mut self, user: ChatId, mut sub: Subscription) -> Result<u64> {
+        if let Some(existing) = self.find(&user, &sub).cloned() {
+            sub.id = existing.id;
+            self.unindex(&user, &existing);
+            if self.is_chat_active(&user) {
+                self.index(user, &sub);
+            }
+            let user_subs = self.user_subs.entry(user).or_default();
+            if let Some(stored) = user_subs.iter_mut().find(|stored| stored.id == sub.id) {
+                *stored = sub.clone();
+            }
+            return Ok(sub.id);
+        }
+        sub.id = self.next_sub_id.max(1);
+        self.insert(user, sub.clone());
+        Ok(sub.id)
+    }
+
+    fn remove_sub(&mut self, user: &ChatId, id: u64) -> Result<Subscription> {
+        let user_subs = self.user_subs.get_mut(user).ok_or(eyre!("No subs"))?;
+        let index = user_subs
+            .iter()
+            .position(|sub| sub.id == id)
+            .ok_or(eyre!("unknown subscription {}", id))?;
+        let subscription = user_subs.remove(index);
+        self.unindex(user, &subscription);
+        Ok(subscription)
+    }
+
+    fn get_matching_subs(
+        &self,
+        chain_id: &u32,
+        token_address: &Option<Address>,
+        token_sender_receiver: &Address,
+    ) -> &[(ChatId, Subscription)] {
+        self.subs
+            .get(chain_id)
+            .and_then(|tokens| tokens.get(token_address))
+            .and_then(|addresses| addresses.get(token_sender_receiver))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn get_token_subs(&self, chain_id: &u32, token_address: &Address) -> &[(ChatId, Subscription)] {
+        self.token_subs
+            .get(chain_id)
+            .and_then(|tokens| tokens.get(token_address))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn get_approval_subs(&self, chain_id: &u32, owner: &Address) -> &[(ChatId, Subscription)] {
+        self.approval_subs
+            .get(chain_id)
+            .and_then(|owners| owners.get(owner))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn get_event_subs(&self, chain_id: &u32, contract: &Address) -> &[(ChatId, Subscription)] {
+        self.event_subs
+            .get(chain_id)
+            .and_then(|contracts| contracts.get(contract))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn get_user_subs(&self, user: &ChatId) -> Option<&Vec<Subscription>> {
+        self.user_subs.get(user)
+    }
+
+    fn get_watch_set(&self, chain_id: &u32) -> WatchSet {
+        let mut watch = WatchSet::default();
+        for (token, addresses) in self.subs.get(chain_id).into_iter().flatten() {
+            match token {
+                Some(token) => {
+                    watch.tokens.insert(*token);
+                    watch.addresses.extend(addresses.keys());
+                }
+                None => watch.any_token_addresses.extend(addresses.keys()),
+            }
+        }
+        watch.token_wide.extend(
+            self.token_subs
+                .get(chain_id)
+                .into_iter()
+                .flatten()
+                .map(|(token, _)| *token),
+        );
+        watch.approval_owners.extend(
+            self.approval_subs
+                .get(chain_id)
+                .into_iter()
+                .flatten()
+                .map(|(owner, _)| *owner),
+        );
+        for (contract, subscribers) in self.event_subs.get(chain_id).into_iter().flatten() {
+            watch.event_contracts.insert(*contract);
+            watch.event_topics.extend(
+                subscribers
+                    .iter()
+                    .filter_map(|(_, sub)| sub.event.as_ref().map(CustomEvent::topic)),
+            );
+        }
+        watch
+    }
+
+    fn get_token_metadata(
+        &self,
+        chain_id: &u32,
+        token_address: &Address,
+    ) -> Option<&(String, String, u8)> {
+        self.cached_token_metadata.get(chain_id)?.get(token_address)
+    }
+
+    fn insert_token_metadata(
+        &mut self,
+        chain_id: &u32,
+        token_address: Address,
+        metadata: (String, String, u8),
+    ) -> Result<()> {
+        self.cached_token_metadata
+            .entry(*chain_id)
+            .or_default()
+            .insert(token_address, metadata);
+        Ok(())
+    }
+
+    fn get_chain_cursor(&self, chain_id: &u32) -> Option<u64> {
+        self.chain_cursors.get(chain_id).copied()
+    }
+
+    fn set_chain_cursor(&mut self, chain_id: &u32, block: u64) -> Result<()> {
+        self.chain_cursors.insert(*chain_id, block);
+        if let Some(notified) = self.notified_logs.get_mut(chain_id) {
+            notified.retain(|_, notified_block| *notified_block > block);
+        }
+        Ok(())
+    }
+
+    fn mark_notified(&mut self, chain_id: &u32, key: LogKey, block: u64) -> Result<bool> {
+        let notified = self.notified_logs.entry(*chain_id).or_default();
+        if notified.contains_key(&key) {
+            return Ok(false);
+        }
+        notified.insert(key, block);
+        Ok(true)
+    }
+
+    fn unmark_notified(&mut self, chain_id: &u32, key: &LogKey) -> Result<()> {
+        if let Some(notified) = self.notified_logs.get_mut(chain_id) {
+            notified.remove(key);
+        }
+        Ok(())
+    }
+
+    fn set_chat_active(&mut self, user: ChatId, active: bool) -> Result<bool> {
+        let changed = if active {
+            self.inactive_chats.remove(&user)
+        } else {
+            self.inactive_chats.insert(user)
+        };
+        if changed {
+            let subs = self.user_subs.get(&user).cloned().unwrap_or_default();
+            for sub in &subs {
+                if active {
+                    self.index(user, sub);
+                } else {
+                    self.unindex(&user, sub);
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    fn is_chat_active(&self, user: &ChatId) -> bool {
+        !self.inactive_chats.contains(user)
+    }
+}
+
+/// Stored instead of the token or watched address of subscriptions matching any of them.
+const ANY_ADDRESS: &str = "*";
+
+fn encode_address(address: &Option<Address>) -> String {
+    address.map_or(ANY_ADDRESS.to_string(), |address| format!("{:#x}", address))
+}
+
+fn decode_address(address: &str) -> Result<Option<Address>> {
+    if address == ANY_ADDRESS {
+        return Ok(None);
+    }
+    Ok(Some(Address::from_str(address)?))
+}
+
+/// SQLite integers are signed, log indexes above `i64::MAX` (native transfers
+/// and custom events) are stored as their two's complement.
+fn encode_log_index(log_index: u64) -> i64 {
+    log_index as i64
+}
+
+fn decode_log_index(log_index: i64) -> u64 {
+    log_index as u64
+}
+
+/// Schema migrations, applied in order and tracked through `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE subscriptions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL,
+        chain_id INTEGER NOT NULL,
+        token_address TEXT NOT NULL,
+        token_sender_receiver TEXT NOT NULL,
+        UNIQUE (chat_id, chain_id, token_address, token_sender_receiver)
+    );
+    CREATE TABLE token_metadata (
+        chain_id INTEGER NOT NULL,
+        token_address TEXT NOT NULL,
+        name TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        decimals INTEGER NOT NULL,
+        PRIMARY KEY (chain_id, token_address)
+    );
+",
+    "
+    CREATE TABLE chain_cursors (
+        chain_id INTEGER PRIMARY KEY,
+        block INTEGER NOT NULL
+    );
+    CREATE TABLE notified_logs (
+        chain_id INTEGER NOT NULL,
+        tx_hash TEXT NOT NULL,
+        log_index INTEGER NOT NULL,
+        block INTEGER NOT NULL,
+        PRIMARY KEY (chain_id, tx_hash, log_index)
+    );
+",
+    "
+    CREATE TABLE inactive_chats (
+        chat_id INTEGER PRIMARY KEY
+    );
+",
+    "
+    ALTER TABLE subscriptions ADD COLUMN label TEXT;
+",
+    "
+    ALTER TABLE subscriptions ADD COLUMN min_amount TEXT;
+",
+    "
+    ALTER TABLE subscriptions ADD COLUMN direction TEXT NOT NULL DEFAULT 'both';
+",
+    // SQLite can't change a UNIQUE constraint, the table is rebuilt with the kind in it.
+    "
+    CREATE TABLE subscriptions_v2 (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL,
+        chain_id INTEGER NOT NULL,
+        token_address TEXT NOT NULL,
+        token_sender_receiver TEXT NOT NULL,
+        label TEXT,
+        min_amount TEXT,
+        direction TEXT NOT NULL DEFAULT 'both',
+        kind TEXT NOT NULL DEFAULT 'transfers',
+        UNIQUE (chat_id, chain_id, token_address, token_sender_receiver, kind)
+    );
+    INSERT INTO subscriptions_v2
+        (id, chat_id, chain_id, token_address, token_sender_receiver, label, min_amount, direction)
+        SELECT id, chat_id, chain_id, token_address, token_sender_receiver, label, min_amount,
+        direction FROM subscriptions;
+    -- Ids of deleted subscriptions must not be handed out again.
+    DELETE FROM sqlite_sequence WHERE name = 'subscriptions_v2';
+    INSERT INTO sqlite_sequence (name, seq)
+        SELECT 'subscriptions_v2', seq FROM sqlite_sequence WHERE name = 'subscriptions';
+    DROP TABLE subscriptions;
+    ALTER TABLE subscriptions_v2 RENAME TO subscriptions;
+",
+    // Same again for the event of custom event subscriptions, empty for the others.
+    "
+    CREATE TABLE subscriptions_v3 (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL,
+        chain_id INTEGER NOT NULL,
+        token_address TEXT NOT NULL,
+        token_sender_receiver TEXT NOT NULL,
+        label TEXT,
+        min_amount TEXT,
+        direction TEXT NOT NULL DEFAULT 'both',
+        kind TEXT NOT NULL DEFAULT 'transfers',
+        event TEXT NOT NULL DEFAULT '',
+        UNIQUE (chat_id, chain_id, token_address, token_sender_receiver, kind, event)
+    );
+    INSERT INTO subscriptions_v3
+        (id, chat_id, chain_id, token_address, token_sender_receiver, label, min_amount, direction,
+        kind)
+        SELECT id, chat_id, chain_id, token_address, token_sender_receiver, label, min_amount,
+        direction, kind FROM subscriptions;
+    DELETE FROM sqlite_sequence WHERE name = 'subscriptions_v3';
+    INSERT INTO sqlite_sequence (name, seq)
+        SELECT 'subscriptions_v3', seq FROM sqlite_sequence WHERE name = 'subscriptions';
+    DROP TABLE subscriptions;
+    ALTER TABLE subscriptions_v3 RENAME TO subscriptions;
+",
+    "
+    ALTER TABLE subscriptions ADD COLUMN filter TEXT;
+",
+];
+
+/// SQLite backed store. Reads are served from an in memory copy loaded on open,
+/// writes go to the database first and then to the copy.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    cache: MemoryStore,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self> {
+        migrate(&mut conn)?;
+        let cache = load(&conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            cache,
+        })
+    }
+
+    fn conn(&mut self) -> &mut Connection {
+        self.conn
+            .get_mut()
+            .expect("connection lock is never poisoned")
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as i64 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn load(conn: &Connection) -> Result<MemoryStore> {
+    let mut cache = MemoryStore::new();
+
+    let mut stmt = conn.prepare(
+        "SELECT id, chat_id, chain_id, token_address, token_sender_receiver, label, min_amount,
+        direction, kind, event, filter FROM subscriptions ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, u32>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+            row.get::<_, Option<String>>(5)?,
+            row.get::<_, Option<String>>(6)?,
+            row.get::<_, String>(7)?,
+            row.get::<_, String>(8)?,
+            row.get::<_, String>(9)?,
+            row.get::<_, Option<String>>(10)?,
+        ))
+    })?;
+    for row in rows {
+        let (
+            id,
+            chat_id,
+            chain_id,
+            token_address,
+            token_sender_receiver,
+            label,
+            min_amount,
+            direction,
+            kind,
+            event,
+            filter,
+        ) = row?;
+        cache.insert(
+            ChatId(chat_id),
+            Subscription {
+                id,
+                chain_id,
+                token_address: decode_address(&token_address)?,
+                token_sender_receiver: decode_address(&token_sender_receiver)?,
+                label,
+                min_amount: min_amount
+                    .map(|min_amount| U256::from_dec_str(&min_amount))
+                    .transpose()?,
+                direction: direction.parse()?,
+                kind: kind.parse()?,
+                event: (!event.is_empty())
+                    .then(|| CustomEvent::from_json(&event))
+                    .transpose()?,
+                filter: filter
+                    .map(|filter| filter.parse::<TransferFilter>())
+                    .transpose()?,
+            },
+        );
+    }
+
+    let mut stmt = conn.prepare("SELECT chat_id FROM inactive_chats")?;
+    let rows = stmt.query_map([], |row| row.get::<_, i64>(0))?;
+    for row in rows {
+        cache.set_chat_active(ChatId(row?), false)?;
+    }
+
+    let mut stmt =
+        conn.prepare("SELECT chain_id, token_address, name, symbol, decimals FROM token_metadata")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u32>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, u8>(4)?,
+        ))
+    })?;
+    for row in rows {
+        let (chain_id, token_address, name, symbol, decimals) = row?;
+        cache.insert_token_metadata(
+            &chain_id,
+            Address::from_str(&token_address)?,
+            (name, symbol, decimals),
+        )?;
+    }
+
+    let mut stmt = conn.prepare("SELECT chain_id, block FROM chain_cursors")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u64>(1)?)))?;
+    for row in rows {
+        let (chain_id, block) = row?;
+        cache.set_chain_cursor(&chain_id, block)?;
+    }
+
+    let mut stmt = conn.prepare("SELECT chain_id, tx_hash, log_index, block FROM notified_logs")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u32>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, u64>(3)?,
+        ))
+    })?;
+    for row in rows {
+        let (chain_id, tx_hash, log_index, block) = row?;
+        let key = (H256::from_str(&tx_hash)?, decode_log_index(log_index));
+        cache.mark_notified(&chain_id, key, block)?;
+    }
+
+    Ok(cache)
+}
+
+impl SubscriptionStore for SqliteStore {
+    fn insert_sub(&mut self, user: ChatId, mut sub: Subscription) -> Result<u64> {
+        if let Some(id) = self.cache.find(&user, &sub).map(|existing| existing.id) {
+            self.conn().execute(
+                "UPDATE subscriptions SET label = ?1, min_amount = ?2, direction = ?3, filter = ?4
+                WHERE id = ?5 AND chat_id = ?6",
+                params![
+                    sub.label,
+                    sub.min_amount.map(|min_amount| min_amount.to_string()),
+                    sub.direction.as_str(),
+                    sub.filter.as_ref().map(ToString::to_string),
+                    id,
+                    user.0
+                ],
+            )?;
+            return self.cache.insert_sub(user, sub);
+        }
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO subscriptions
+            (chat_id, chain_id, token_address, token_sender_receiver, label, min_amount, direction,
+            kind, event, filter)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                user.0,
+                sub.chain_id,
+                encode_address(&sub.token_address),
+                encode_address(&sub.token_sender_receiver),
+                sub.label,
+                sub.min_amount.map(|min_amount| min_amount.to_string()),
+                sub.direction.as_str(),
+                sub.kind.as_str(),
+                sub.event
+                    .as_ref()
+                    .map(CustomEvent::to_json)
+                    .unwrap_or_default(),
+                sub.filter.as_ref().map(ToString::to_string)
+            ],
+        )?;
+        // AUTOINCREMENT ids are never reused, even after the row is deleted.
+        sub.id = conn.last_insert_rowid() as u64;
+        self.cache.insert(user, sub.clone());
+        Ok(sub.id)
+    }
+
+    fn remove_sub(&mut self, user: &ChatId, id: u64) -> Result<Subscription> {
+        let deleted = self.conn().execute(
+            "DELETE FROM subscriptions WHERE id = ?1 AND chat_id = ?2",
+            params![id, user.0],
+        )?;
+        if deleted == 0 {
+            return Err(eyre!("unknown subscription {}", id));
+        }
+        self.cache.remove_sub(user, id)
+    }
+
+    fn get_matching_subs(
+        &self,
+        chain_id: &u32,
+        token_address: &Option<Address>,
+        token_sender_receiver: &Address,
+    ) -> &[(ChatId, Subscription)] {
+        self.cache
+            .get_matching_subs(chain_id, token_address, token_sender_receiver)
+    }
+
+    fn get_token_subs(&self, chain_id: &u32, token_address: &Address) -> &[(ChatId, Subscription)] {
+        self.cache.get_token_subs(chain_id, token_address)
+    }
+
+    fn get_approval_subs(&self, chain_id: &u32, owner: &Address) -> &[(ChatId, Subscription)] {
+        self.cache.get_approval_subs(chain_id, owner)
+    }
+
+    fn get_event_subs(&self, chain_id: &u32, contract: &Address) -> &[(ChatId, Subscription)] {
+        self.cache.get_event_subs(chain_id, contract)
+    }
+
+    fn get_user_subs(&self, user: &ChatId) -> Option<&Vec<Subscription>> {
+        self.cache.get_user_subs(user)
+    }
+
+    fn get_watch_set(&self, chain_id: &u32) -> WatchSet {
+        self.cache.get_watch_set(chain_id)
+    }
+
+    fn get_token_metadata(
+        &self,
+        chain_id: &u32,
+        token_address: &Address,
+    ) -> Option<&(String, String, u8)> {
+        self.cache.get_token_metadata(chain_id, token_address)
+    }
+
+    fn insert_token_metadata(
+        &mut self,
+        chain_id: &u32,
+        token_address: Address,
+        metadata: (String, String, u8),
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO token_metadata (chain_id, token_address, name, symbol, decimals)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                chain_id,
+                format!("{:#x}", token_address),
+                metadata.0,
+                metadata.1,
+                metadata.2
+            ],
+        )?;
+        self.cache
+            .insert_token_metadata(chain_id, token_address, metadata)
+    }
+
+    fn get_chain_cursor(&self, chain_id: &u32) -> Option<u64> {
+        self.cache.get_chain_cursor(chain_id)
+    }
+
+    fn set_chain_cursor(&mut self, chain_id: &u32, block: u64) -> Result<()> {
+        let tx = self.conn().transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO chain_cursors (chain_id, block) VALUES (?1, ?2)",
+            params![chain_id, block],
+        )?;
+        tx.execute(
+            "DELETE FROM notified_logs WHERE chain_id = ?1 AND block <= ?2",
+            params![chain_id, block],
+        )?;
+        tx.commit()?;
+        self.cache.set_chain_cursor(chain_id, block)
+    }
+
+    fn mark_notified(&mut self, chain_id: &u32, key: LogKey, block: u64) -> Result<bool> {
+        if !self.cache.mark_notified(chain_id, key, block)? {
+            return Ok(false);
+        }
+        self.conn().execute(
+            "INSERT OR IGNORE INTO notified_logs (chain_id, tx_hash, log_index, block)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                chain_id,
+                format!("{:#x}", key.0),
+                encode_log_index(key.1),
+                block
+            ],
+        )?;
+        Ok(true)
+    }
+
+    fn unmark_notified(&mut self, chain_id: &u32, key: &LogKey) -> Result<()> {
+        // Forgotten in memory first, a failed delete must not keep the
+        // reorged log from being notified again once it is mined again.
+        self.cache.unmark_notified(chain_id, key)?;
+        self.conn().execute(
+            "DELETE FROM notified_logs WHERE chain_id = ?1 AND tx_hash = ?2 AND log_index = ?3",
+            params![chain_id, format!("{:#x}", key.0), encode_log_index(key.1)],
+        )?;
+        Ok(())
+    }
+
+    fn set_chat_active(&mut self, user: ChatId, active: bool) -> Result<bool> {
+        if self.cache.is_chat_active(&user) == active {
+            return Ok(false);
+        }
+        if active {
+            self.conn()
+                .execute("DELETE FROM inactive_chats WHERE chat_id = ?1", [user.0])?;
+        } else {
+            self.conn().execute(
+                "INSERT OR IGNORE INTO inactive_chats (chat_id) VALUES (?1)",
+                [user.0],
+            )?;
+        }
+        self.cache.set_chat_active(user, active)
+    }
+
+    fn is_chat_active(&self, user: &ChatId) -> bool {
+        self.cache.is_chat_active(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        chain_listener::{event_key, internal_key, NATIVE_LOG_INDEX},
+        state::Direction,
+    };
+    use std::collections::BTreeSet;
+
+    fn sub(token: u64, user: u64) -> Subscription {
+        Subscription {
+            id: 0,
+            chain_id: 1,
+            token_address: Some(Address::from_low_u64_be(token)),
+            token_sender_receiver: Some(Address::from_low_u64_be(user)),
+            label: None,
+            min_amount: None,
+            direction: Direction::Both,
+            kind: SubKind::Transfers,
+            event: None,
+            filter: None,
+        }
+    }
+
+    fn event_sub(contract: u64, declaration: &str) -> Subscription {
+        Subscription {
+            token_sender_receiver: None,
+            kind: SubKind::Events,
+            event: Some(CustomEvent::parse(declaration).unwrap()),
+            ..sub(contract, 0)
+        }
+    }
+
+    fn exercise(store: &mut dyn SubscriptionStore) {
+        let chat = ChatId(7);
+        let first = store.insert_sub(chat, sub(1, 10)).unwrap();
+        assert_eq!(store.insert_sub(chat, sub(1, 10)).unwrap(), first);
+        // Subscribing again with another threshold updates the subscription.
+        let threshold = Subscription {
+            min_amount: Some(U256::exp10(18)),
+            direction: Direction::Out,
+            ..sub(1, 10)
+        };
+        assert_eq!(store.insert_sub(chat, threshold.clone()).unwrap(), first);
+        assert_eq!(store.get_user_subs(&chat).unwrap().len(), 1);
+        let matching = store.get_matching_subs(
+            &1,
+            &Some(Address::from_low_u64_be(1)),
+            &Address::from_low_u64_be(10),
+        );
+        assert_eq!(matching.len(), 1);
+        assert_eq!(
+            matching[0].1,
+            Subscription {
+                id: first,
+                ..threshold.clone()
+            }
+        );
+        assert_eq!(
+            store.get_user_subs(&chat).unwrap()[0],
+            Subscription {
+                id: first,
+                ..threshold
+            }
+        );
+        let second = store.insert_sub(chat, sub(2, 20)).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(store.get_user_subs(&chat).unwrap().len(), 2);
+        assert_eq!(store.get_watch_set(&1).tokens.len(), 2);
+        assert_eq!(
+            store.get_matching_subs(
+                &1,
+                &Some(Address::from_low_u64_be(1)),
+                &Address::from_low_u64_be(10)
+            )[0]
+            .0,
+            chat
+        );
+
+        assert!(store.remove_sub(&ChatId(8), first).is_err());
+        assert!(store
+            .remove_sub(&chat, first)
+            .unwrap()
+            .same_target(&sub(1, 10)));
+        assert!(store.remove_sub(&chat, first).is_err());
+        // Ids are not reused nor shifted by removals.
+        let third = store.insert_sub(chat, sub(1, 10)).unwrap();
+        assert!(third > second);
+        assert!(store.remove_sub(&chat, third).is_ok());
+        assert_eq!(store.get_user_subs(&chat).unwrap()[0].id, second);
+        assert!(store
+            .get_matching_subs(
+                &1,
+                &Some(Address::from_low_u64_be(1)),
+                &Address::from_low_u64_be(10)
+            )
+            .is_empty());
+
+        let any_token = Subscription {
+            token_address: None,
+            ..sub(0, 40)
+        };
+        let fourth = store.insert_sub(chat, any_token.clone()).unwrap();
+        assert_eq!(
+            store.get_matching_subs(&1, &None, &Address::from_low_u64_be(40))[0].1,
+            Subscription {
+                id: fourth,
+                ..any_token
+            }
+        );
+        assert!(store.remove_sub(&chat, fourth).is_ok());
+
+        let token_wide = Subscription {
+            token_sender_receiver: None,
+            min_amount: Some(U256::from(1_000)),
+            ..sub(5, 0)
+        };
+        let fifth = store.insert_sub(chat, token_wide).unwrap();
+        assert_eq!(
+            store.get_token_subs(&1, &Address::from_low_u64_be(5))[0]
+                .1
+                .id,
+            fifth
+        );
+        assert!(store
+            .get_matching_subs(&1, &Some(Address::from_low_u64_be(5)), &Address::zero())
+            .is_empty());
+        assert_eq!(
+            store.get_watch_set(&1).token_wide,
+            BTreeSet::from([Address::from_low_u64_be(5)])
+        );
+        assert!(store.remove_sub(&chat, fifth).is_ok());
+        assert!(store
+            .get_token_subs(&1, &Address::from_low_u64_be(5))
+            .is_empty());
+
+        // Watching the approvals of a watched address is a separate subscription.
+        let approvals = Subscription {
+            kind: SubKind::Approvals,
+            ..sub(2, 20)
+        };
+        let sixth = store.insert_sub(chat, approvals).unwrap();
+        assert_ne!(sixth, second);
+        assert_eq!(
+            store.get_approval_subs(&1, &Address::from_low_u64_be(20))[0]
+                .1
+                .id,
+            sixth
+        );
+        assert_eq!(
+            store.get_watch_set(&1).approval_owners,
+            BTreeSet::from([Address::from_low_u64_be(20)])
+        );
+        assert!(store.remove_sub(&chat, sixth).is_ok());
+        assert!(store
+            .get_approval_subs(&1, &Address::from_low_u64_be(20))
+            .is_empty());
+
+        // Every event of a contract is a subscription of its own.
+        let paused = store.insert_sub(chat, event_sub(6, "Paused()")).unwrap();
+        let unpaused = store.insert_sub(chat, event_sub(6, "Unpaused()")).unwrap();
+        assert_ne!(paused, unpaused);
+        assert_eq!(
+            store.insert_sub(chat, event_sub(6, "Paused()")).unwrap(),
+            paused
+        );
+        assert_eq!(
+            store.get_event_subs(&1, &Address::from_low_u64_be(6)).len(),
+            2
+        );
+        let watch = store.get_watch_set(&1);
+        assert_eq!(
+            watch.event_contracts,
+            BTreeSet::from([Address::from_low_u64_be(6)])
+        );
+        assert_eq!(
+            watch.event_topics,
+            BTreeSet::from([
+                CustomEvent::parse("Paused()").unwrap().topic(),
+                CustomEvent::parse("Unpaused()").unwrap().topic()
+            ])
+        );
+        assert!(watch.tokens.iter().all(|token| token.to_low_u64_be() != 6));
+        assert!(store.remove_sub(&chat, paused).is_ok());
+        assert!(store.remove_sub(&chat, unpaused).is_ok());
+        assert!(store.get_watch_set(&1).event_contracts.is_empty());
+        let watch = store.get_watch_set(&1);
+        assert!(watch.any_token_addresses.is_empty());
+        assert_eq!(
+            watch.tokens.into_iter().collect::<Vec<_>>(),
+            vec![Address::from_low_u64_be(2)]
+        );
+        assert_eq!(
+            watch.addresses.into_iter().collect::<Vec<_>>(),
+            vec![Address::from_low_u64_be(20)]
+        );
+
+        let key = (H256::from_low_u64_be(1), 0);
+        assert!(store.mark_notified(&1, key, 100).unwrap());
+        assert!(!store.mark_notified(&1, key, 100).unwrap());
+        store.unmark_notified(&1, &key).unwrap();
+        assert!(store.mark_notified(&1, key, 100).unwrap());
+        store.set_chain_cursor(&1, 99).unwrap();
+        assert!(!store.mark_notified(&1, key, 100).unwrap());
+        store.set_chain_cursor(&1, 100).unwrap();
+        assert_eq!(store.get_chain_cursor(&1), Some(100));
+        assert!(store.mark_notified(&1, key, 100).unwrap());
+
+        assert!(store.set_chat_active(chat, false).unwrap());
+        assert!(!store.set_chat_active(chat, false).unwrap());
+        assert!(!store.is_chat_active(&chat));
+        assert!(store.get_watch_set(&1).tokens.is_empty());
+        store.insert_sub(chat, sub(3, 30)).unwrap();
+        assert!(store.get_watch_set(&1).tokens.is_empty());
+        assert_eq!(store.get_user_subs(&chat).unwrap().len(), 2);
+        assert!(store.set_chat_active(chat, true).unwrap());
+        assert_eq!(store.get_watch_set(&1).tokens.len(), 2);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        exercise(&mut SqliteStore::from_connection(Connection::open_in_memory().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_store_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("subs.db");
+        let token = Address::from_low_u64_be(1);
+        {
+            let mut store = SqliteStore::open(&path).unwrap();
+            store.insert_sub(ChatId(7), sub(1, 10)).unwrap();
+            let labeled = Subscription {
+                label: Some("treasury".to_string()),
+                min_amount: Some(U256::exp10(20)),
+                direction: Direction::In,
+                filter: Some("amount < 1m and block > 40".parse().unwrap()),
+                ..sub(1, 10)
+            };
+            assert_eq!(store.insert_sub(ChatId(7), labeled).unwrap(), 1);
+            store.insert_sub(ChatId(8), sub(1, 10)).unwrap();
+            store
+                .insert_token_metadata(&1, token, ("Token".into(), "TKN".into(), 18))
+                .unwrap();
+            store.set_chain_cursor(&1, 41).unwrap();
+            store
+                .mark_notified(&1, (H256::from_low_u64_be(1), 3), 42)
+                .unwrap();
+            store.set_chat_active(ChatId(8), false).unwrap();
+            let any_token = Subscription {
+                token_address: None,
+                ..sub(0, 30)
+            };
+            store.insert_sub(ChatId(9), any_token).unwrap();
+            let token_wide = Subscription {
+                token_sender_receiver: None,
+                min_amount: Some(U256::exp10(24)),
+                ..sub(1, 0)
+            };
+            store.insert_sub(ChatId(9), token_wide).unwrap();
+            let approvals = Subscription {
+                kind: SubKind::Approvals,
+                ..sub(1, 10)
+            };
+            store.insert_sub(ChatId(7), approvals).unwrap();
+            store
+                .insert_sub(ChatId(9), event_sub(2, "AddedOwner(address owner)"))
+                .unwrap();
+        }
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        let subs = store.get_user_subs(&ChatId(7)).unwrap();
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs[1].kind, SubKind::Approvals);
+        assert!(subs[0].same_target(&sub(1, 10)));
+        assert_eq!(subs[0].id, 1);
+        assert_eq!(subs[0].label.as_deref(), Some("treasury"));
+        assert_eq!(subs[0].min_amount, Some(U256::exp10(20)));
+        assert_eq!(subs[0].direction, Direction::In);
+        assert_eq!(
+            subs[0].filter.as_ref().map(ToString::to_string).as_deref(),
+            Some("amount < 1m and block > 40")
+        );
+        assert_eq!(
+            store
+                .get_matching_subs(&1, &Some(token), &Address::from_low_u64_be(10))
+                .len(),
+            1
+        );
+        assert!(!store.is_chat_active(&ChatId(8)));
+        assert_eq!(
+            store.get_token_metadata(&1, &token),
+            Some(&("Token".into(), "TKN".into(), 18))
+        );
+        assert_eq!(store.get_chain_cursor(&1), Some(41));
+        assert!(!store
+            .mark_notified(&1, (H256::from_low_u64_be(1), 3), 42)
+            .unwrap());
+        assert_eq!(
+            store.get_watch_set(&1).any_token_addresses,
+            BTreeSet::from([Address::from_low_u64_be(30)])
+        );
+        assert_eq!(store.get_token_subs(&1, &token).len(), 1);
+        assert_eq!(
+            store
+                .get_approval_subs(&1, &Address::from_low_u64_be(10))
+                .len(),
+            1
+        );
+        let events = store.get_event_subs(&1, &Address::from_low_u64_be(2));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].1,
+            Subscription {
+                id: 6,
+                ..event_sub(2, "AddedOwner(address owner)")
+            }
+        );
+        assert_eq!(store.insert_sub(ChatId(9), sub(2, 20)).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_sqlite_notified_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("subs.db");
+        let tx_hash = H256::from_low_u64_be(1);
+        let keys = [
+            (tx_hash, 3),
+            (tx_hash, NATIVE_LOG_INDEX),
+            event_key((tx_hash, 3)),
+            internal_key(tx_hash, 0),
+        ];
+        {
+            let mut store = SqliteStore::open(&path).unwrap();
+            for key in keys {
+                assert!(store.mark_notified(&1, key, 42).unwrap());
+            }
+        }
+        {
+            let mut store = SqliteStore::open(&path).unwrap();
+            for key in keys {
+                assert!(!store.mark_notified(&1, key, 42).unwrap());
+                store.unmark_notified(&1, &key).unwrap();
+            }
+        }
+        let mut store = SqliteStore::open(&path).unwrap();
+        for key in keys {
+            assert!(store.mark_notified(&1, key, 42).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_sqlite_migration_keeps_ids() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&MIGRATIONS[..6].concat()).unwrap();
+        conn.pragma_update(None, "user_version", 6).unwrap();
+        for user in [10, 20] {
+            conn.execute(
+                "INSERT INTO subscriptions (chat_id, chain_id, token_address, token_sender_receiver)
+                VALUES (7, 1, ?1, ?2)",
+                params![
+                    format!("{:#x}", Address::from_low_u64_be(1)),
+                    format!("{:#x}", Address::from_low_u64_be(user))
+                ],
+            )
+            .unwrap();
+        }
+        conn.execute("DELETE FROM subscriptions WHERE id = 2", [])
+            .unwrap();
+
+        let mut store = SqliteStore::from_connection(conn).unwrap();
+        let subs = store.get_user_subs(&ChatId(7)).unwrap();
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].kind, SubKind::Transfers);
+        assert_eq!(subs[0].event, None);
+        assert_eq!(store.insert_sub(ChatId(7), sub(1, 20)).unwrap(), 3);
+    }
+}
@@ -0,0 +1,56 @@
+use ethers::{prelude::abigen, providers::Middleware, types::Address};
+use eyre::{eyre, Result};
+use std::sync::Arc;
+
+abigen!(
+    IToken,
+    r#"[
+        function symbol() public view returns (string memory)
+        function name() public view returns (string memory)
+        function decimals() public view virtual returns (uint8)
+        function supportsInterface(bytes4 interfaceId) external view returns (bool)
+    ]"#,
+);
+
+/// ERC165 interface ids of the NFT standards.
+const ERC721_INTERFACE: [u8; 4] = [0x80, 0xac, 0x58, 0xcd];
+const ERC1155_INTERFACE: [u8; 4] = [0xd9, 0xb6, 0x7a, 0x26];
+
+/// Name, symbol and decimals of an ERC20 token. ERC721 and ERC1155 collections
+/// have no decimals, their tokens are counted one by one. Their name and symbol
+/// are optional, the address stands in for a missing name.
+pub async fn fetch_token_metadata<M: Middleware + 'static>(
+    client: Arc<M>,
+    token_address: Address,
+) -> Result<(String, String, u8)> {
+    let contract = IToken::new(token_address, client);
+    let name = contract.name().call().await.ok();
+    let symbol = contract.symbol().call().await.ok();
+    if let (Some(name), Some(symbol)) = (&name, &symbol) {
+        if let Ok(decimals) = contract.decimals().call().await {
+            return Ok((name.clone(), symbol.clone(), decimals));
+        }
+    }
+    if supports_nft(&contract).await {
+        let name = name.unwrap_or_else(|| format!("{:#x}", token_address));
+        return Ok((name, symbol.unwrap_or_default(), 0));
+    }
+    Err(eyre!("Contract Call failed"))
+}
+
+/// Whether `token_address` is an ERC721 or ERC1155 collection, as told by ERC165.
+pub async fn is_collection<M: Middleware + 'static>(
+    client: Arc<M>,
+    token_address: Address,
+) -> bool {
+    supports_nft(&IToken::new(token_address, client)).await
+}
+
+async fn supports_nft<M: Middleware + 'static>(contract: &IToken<M>) -> bool {
+    for interface in [ERC721_INTERFACE, ERC1155_INTERFACE] {
+        if let Ok(true) = contract.supports_interface(interface).call().await {
+            return true;
+        }
+    }
+    false
+}
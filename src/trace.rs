@@ -0,0 +1,207 @@
+use ethers::types::{
+    Action, Address, CallFrame, CallType, GethTrace, GethTraceFrame, NameOrAddress, Res, Trace,
+    TxHash, U256,
+};
+
+use crate::transfer::{Transfer, Transferred, NATIVE};
+
+/// Native currency moved by a call made from within a transaction, numbered in
+/// the order the calls were made. Top level values are left to the block scan.
+pub type InternalTransfer = (TxHash, u64, Transfer);
+
+fn native(from: Address, to: Address, value: U256) -> Transfer {
+    Transfer {
+        token: NATIVE,
+        from,
+        to,
+        transferred: Transferred::Amount(value),
+    }
+}
+
+/// Internal transfers found in the result of `trace_block`. Calls reverted by
+/// themselves or by one of their callers are skipped.
+pub fn from_parity(traces: &[Trace]) -> Vec<InternalTransfer> {
+    let mut transfers = Vec::new();
+    let mut current: Option<TxHash> = None;
+    let mut failed: Vec<&[usize]> = Vec::new();
+    let mut index = 0;
+    for trace in traces {
+        // Block rewards belong to no transaction.
+        let Some(tx_hash) = trace.transaction_hash else {
+            continue;
+        };
+        if current != Some(tx_hash) {
+            current = Some(tx_hash);
+            failed.clear();
+            index = 0;
+        }
+        if trace.error.is_some() {
+            failed.push(&trace.trace_address);
+        }
+        if trace.trace_address.is_empty()
+            || failed
+                .iter()
+                .any(|address| trace.trace_address.starts_with(address))
+        {
+            continue;
+        }
+        let transfer = match (&trace.action, &trace.result) {
+            (Action::Call(call), _) if call.call_type == CallType::Call => {
+                native(call.from, call.to, call.value)
+            }
+            (Action::Create(create), Some(Res::Create(result))) => {
+                native(create.from, result.address, create.value)
+            }
+            (Action::Suicide(suicide), _) => {
+                native(suicide.address, suicide.refund_address, suicide.balance)
+            }
+            _ => continue,
+        };
+        if transfer.amount().is_zero() {
+            continue;
+        }
+        transfers.push((tx_hash, index, transfer));
+        index += 1;
+    }
+    transfers
+}
+
+/// Internal transfers found in the result of `debug_traceBlockByNumber` with the
+/// call tracer, which lists one call tree per transaction of the block, in order.
+pub fn from_geth(traces: &[GethTrace], tx_hashes: &[TxHash]) -> Vec<InternalTransfer> {
+    let mut transfers = Vec::new();
+    for (trace, tx_hash) in traces.iter().zip(tx_hashes) {
+        if let GethTrace::Known(GethTraceFrame::CallTracer(frame)) = trace {
+            let mut found = Vec::new();
+            walk(frame, true, &mut found);
+            transfers.extend(
+                found
+                    .into_iter()
+                    .zip(0..)
+                    .map(|(transfer, index)| (*tx_hash, index, transfer)),
+            );
+        }
+    }
+    transfers
+}
+
+fn walk(frame: &CallFrame, top: bool, found: &mut Vec<Transfer>) {
+    // Everything below a reverted call is reverted as well.
+    if frame.error.is_some() {
+        return;
+    }
+    let moves_value = matches!(
+        frame.typ.as_str(),
+        "CALL" | "CREATE" | "CREATE2" | "SELFDESTRUCT"
+    );
+    if let (false, true, Some(value), Some(NameOrAddress::Address(to))) =
+        (top, moves_value, frame.value, &frame.to)
+    {
+        if !value.is_zero() {
+            found.push(native(frame.from, *to, value));
+        }
+    }
+    for call in frame.calls.iter().flatten() {
+        walk(call, false, found);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ethers::types::{ActionType, Bytes, Call, H256};
+
+    fn address(byte: u8) -> Address {
+        Address::repeat_byte(byte)
+    }
+
+    fn parity(tx: u8, trace_address: Vec<usize>, call_type: CallType, value: u64) -> Trace {
+        Trace {
+            action: Action::Call(Call {
+                from: address(tx),
+                to: address(trace_address.len() as u8 + 0x10),
+                value: value.into(),
+                gas: U256::zero(),
+                input: Bytes::default(),
+                call_type,
+            }),
+            result: None,
+            trace_address,
+            subtraces: 0,
+            transaction_position: Some(tx as usize),
+            transaction_hash: Some(H256::repeat_byte(tx)),
+            block_number: 1,
+            block_hash: H256::zero(),
+            action_type: ActionType::Call,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_from_parity() {
+        let mut reverted = parity(2, vec![0], CallType::Call, 5);
+        reverted.error = Some("Reverted".to_string());
+        let traces = [
+            parity(1, vec![], CallType::Call, 100),
+            parity(1, vec![0], CallType::Call, 7),
+            parity(1, vec![0, 0], CallType::DelegateCall, 7),
+            parity(1, vec![1], CallType::Call, 0),
+            parity(1, vec![2], CallType::Call, 3),
+            parity(2, vec![], CallType::Call, 0),
+            reverted,
+            parity(2, vec![0, 0], CallType::Call, 9),
+            parity(2, vec![1], CallType::Call, 1),
+        ];
+        let transfers = from_parity(&traces);
+        let found: Vec<_> = transfers
+            .iter()
+            .map(|(tx_hash, index, transfer)| (tx_hash.0[0], *index, transfer.amount().as_u64()))
+            .collect();
+        assert_eq!(found, vec![(1, 0, 7), (1, 1, 3), (2, 0, 1)]);
+        assert_eq!(transfers[0].2.from, address(1));
+        assert_eq!(transfers[0].2.to, address(0x11));
+        assert_eq!(transfers[0].2.token, NATIVE);
+    }
+
+    fn frame(typ: &str, to: u8, value: u64, calls: Vec<CallFrame>) -> CallFrame {
+        CallFrame {
+            typ: typ.to_string(),
+            from: address(0xaa),
+            to: Some(NameOrAddress::Address(address(to))),
+            value: Some(value.into()),
+            calls: Some(calls),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_from_geth() {
+        let mut reverted = frame("CALL", 4, 8, vec![frame("CALL", 5, 9, vec![])]);
+        reverted.error = Some("execution reverted".to_string());
+        let first = frame(
+            "CALL",
+            1,
+            100,
+            vec![
+                frame("CALL", 2, 7, vec![frame("DELEGATECALL", 3, 7, vec![])]),
+                reverted,
+                frame("SELFDESTRUCT", 6, 2, vec![]),
+            ],
+        );
+        let mut failed = frame("CALL", 1, 0, vec![frame("CALL", 2, 1, vec![])]);
+        failed.error = Some("out of gas".to_string());
+        let traces: Vec<GethTrace> = [first, failed]
+            .into_iter()
+            .map(|frame| GethTrace::Known(GethTraceFrame::CallTracer(frame)))
+            .collect();
+        let tx_hashes = [H256::repeat_byte(1), H256::repeat_byte(2)];
+        let found: Vec<_> = from_geth(&traces, &tx_hashes)
+            .into_iter()
+            .map(|(tx_hash, index, transfer)| {
+                (tx_hash.0[0], index, transfer.to, transfer.amount().as_u64())
+            })
+            .collect();
+        assert_eq!(found, vec![(1, 0, address(2), 7), (1, 1, address(6), 2)]);
+    }
+}
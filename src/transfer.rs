@@ -0,0 +1,497 @@
+use ethers::{
+    abi::{self, ParamType, Token},
+    types::{Address, Log, H160, H256, U256},
+    utils::keccak256,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Shared by ERC20 and ERC721, which indexes the token id as well.
+pub const TRANSFER: &str = "Transfer(address,address,uint256)";
+pub const TRANSFER_SINGLE: &str = "TransferSingle(address,address,address,uint256,uint256)";
+pub const TRANSFER_BATCH: &str = "TransferBatch(address,address,address,uint256[],uint256[])";
+/// ERC20 calls moving tokens, decoded from transactions not mined yet.
+pub const TRANSFER_CALL: &str = "transfer(address,uint256)";
+pub const TRANSFER_FROM_CALL: &str = "transferFrom(address,address,uint256)";
+
+/// Stands for the chain's native currency wherever a token address is expected,
+/// following the usual 0xEeee…EEeE convention.
+pub const NATIVE: Address = H160([0xee; 20]);
+pub const NATIVE_DECIMALS: u8 = 18;
+
+/// Tokens moved by a transfer log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transferred {
+    /// ERC20 amount, in the token's smallest unit.
+    Amount(U256),
+    /// ERC721 token id.
+    Nft(U256),
+    /// ERC1155 token ids, with the amount moved of each.
+    Ids(Vec<(U256, U256)>),
+}
+
+/// Transfer of any supported token standard, or of the native currency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub token: Address,
+    pub from: Address,
+    pub to: Address,
+    pub transferred: Transferred,
+}
+
+/// Why a log was not decoded as a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reject {
+    /// Not one of the transfer events.
+    UnknownEvent,
+    /// More indexed parameters than the event has, or a batch not indexed as specified.
+    Topics,
+    /// Data of the wrong length for the non-indexed parameters.
+    Data,
+    /// A word holding an address with non-zero upper bytes.
+    Address,
+    /// ERC1155 batch with a different number of ids and amounts.
+    Batch,
+}
+
+impl Reject {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reject::UnknownEvent => "unknown event",
+            Reject::Topics => "unexpected topics",
+            Reject::Data => "unexpected data",
+            Reject::Address => "invalid address",
+            Reject::Batch => "mismatched batch",
+        }
+    }
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Logs rejected by [`Transfer::decode`], by chain id and reason.
+#[derive(Debug, Clone, Default)]
+pub struct RejectedLogs(Arc<Mutex<BTreeMap<(u32, Reject), usize>>>);
+
+impl RejectedLogs {
+    pub fn record(&self, chain_id: u32, reject: Reject) {
+        *self
+            .0
+            .lock()
+            .expect("lock is never poisoned")
+            .entry((chain_id, reject))
+            .or_default() += 1;
+    }
+
+    pub fn counts(&self) -> Vec<(u32, Reject, usize)> {
+        self.0
+            .lock()
+            .expect("lock is never poisoned")
+            .iter()
+            .map(|((chain_id, reject), count)| (*chain_id, *reject, *count))
+            .collect()
+    }
+}
+
+pub fn signature_topic(signature: &str) -> H256 {
+    H256::from(keccak256(signature))
+}
+
+impl Transfer {
+    /// Decodes ERC20 and ERC721 `Transfer` and ERC1155 `TransferSingle` and
+    /// `TransferBatch` logs. Non-standard contracts index fewer parameters, the
+    /// remaining ones are read from data. A `Transfer` indexing all three
+    /// parameters is an ERC721 one, with the token id last.
+    /// Logs are filtered on the node by their indexed addresses, transfers not
+    /// indexing them are only received for token-wide subscriptions.
+    pub fn decode(log: &Log) -> Result<Transfer, Reject> {
+        let topic0 = *log.topics.first().ok_or(Reject::UnknownEvent)?;
+        let (from, to, transferred) = if topic0 == signature_topic(TRANSFER) {
+            let words = words(log, 3)?;
+            let value = U256::from_big_endian(words[2].as_bytes());
+            let transferred = if log.topics.len() == 4 {
+                Transferred::Nft(value)
+            } else {
+                Transferred::Amount(value)
+            };
+            (words[0], words[1], transferred)
+        } else if topic0 == signature_topic(TRANSFER_SINGLE) {
+            let words = words(log, 5)?;
+            let id = U256::from_big_endian(words[3].as_bytes());
+            let amount = U256::from_big_endian(words[4].as_bytes());
+            (words[1], words[2], Transferred::Ids(vec![(id, amount)]))
+        } else if topic0 == signature_topic(TRANSFER_BATCH) {
+            // Arrays are never indexed, their hash would be.
+            if log.topics.len() != 4 {
+                return Err(Reject::Topics);
+            }
+            (log.topics[2], log.topics[3], decode_batch(&log.data)?)
+        } else {
+            return Err(Reject::UnknownEvent);
+        };
+        Ok(Transfer {
+            token: log.address,
+            from: address(from)?,
+            to: address(to)?,
+            transferred,
+        })
+    }
+
+    /// Decodes an ERC20 `transfer` or `transferFrom` call made by `sender` to
+    /// `token`. ERC721 shares the `transferFrom` selector, telling them apart is
+    /// left to the caller.
+    pub fn decode_call(token: Address, sender: Address, input: &[u8]) -> Option<Transfer> {
+        let selector = input.get(..4)?;
+        let from_param = if selector == &keccak256(TRANSFER_CALL)[..4] {
+            false
+        } else if selector == &keccak256(TRANSFER_FROM_CALL)[..4] {
+            true
+        } else {
+            return None;
+        };
+        let mut params = vec![ParamType::Address, ParamType::Uint(256)];
+        if from_param {
+            params.insert(0, ParamType::Address);
+        }
+        let mut tokens = abi::decode(&params, &input[4..]).ok()?.into_iter();
+        let from = match from_param {
+            true => tokens.next()?.into_address()?,
+            false => sender,
+        };
+        Some(Transfer {
+            token,
+            from,
+            to: tokens.next()?.into_address()?,
+            transferred: Transferred::Amount(tokens.next()?.into_uint()?),
+        })
+    }
+
+    /// Compared against subscription minimums: the ERC20 amount, one per ERC721
+    /// token or the total of an ERC1155 transfer.
+    pub fn amount(&self) -> U256 {
+        match &self.transferred {
+            Transferred::Amount(amount) => *amount,
+            Transferred::Nft(_) => U256::one(),
+            Transferred::Ids(ids) => ids.iter().fold(U256::zero(), |total, (_, amount)| {
+                total.saturating_add(*amount)
+            }),
+        }
+    }
+}
+
+/// The `params` static parameters of an event, the indexed ones followed by the
+/// ones in data.
+pub fn words(log: &Log, params: usize) -> Result<Vec<H256>, Reject> {
+    let indexed = &log.topics[1..];
+    if indexed.len() > params {
+        return Err(Reject::Topics);
+    }
+    if log.data.len() != (params - indexed.len()) * 32 {
+        return Err(Reject::Data);
+    }
+    Ok(indexed
+        .iter()
+        .copied()
+        .chain(log.data.chunks(32).map(H256::from_slice))
+        .collect())
+}
+
+pub fn address(word: H256) -> Result<Address, Reject> {
+    if word[..12].iter().any(|byte| *byte != 0) {
+        return Err(Reject::Address);
+    }
+    Ok(Address::from(word))
+}
+
+fn decode_batch(data: &[u8]) -> Result<Transferred, Reject> {
+    let uints = ParamType::Array(Box::new(ParamType::Uint(256)));
+    let mut tokens = abi::decode(&[uints.clone(), uints], data)
+        .map_err(|_| Reject::Data)?
+        .into_iter();
+    let mut next_array = || -> Option<Vec<U256>> {
+        tokens
+            .next()?
+            .into_array()?
+            .into_iter()
+            .map(Token::into_uint)
+            .collect()
+    };
+    let (ids, amounts) = (
+        next_array().ok_or(Reject::Data)?,
+        next_array().ok_or(Reject::Data)?,
+    );
+    if ids.len() != amounts.len() {
+        return Err(Reject::Batch);
+    }
+    Ok(Transferred::Ids(ids.into_iter().zip(amounts).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use proptest::prelude::*;
+
+    fn topic(address: u64) -> H256 {
+        H256::from(Address::from_low_u64_be(address))
+    }
+
+    fn log(signature: &str, topics: &[H256], data: Vec<Token>) -> Log {
+        Log {
+            address: Address::from_low_u64_be(1),
+            topics: [&[signature_topic(signature)], topics].concat(),
+            data: abi::encode(&data).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_decode_erc20() {
+        let transfer = Transfer::decode(&log(
+            TRANSFER,
+            &[topic(2), topic(3)],
+            vec![Token::Uint(U256::from(500))],
+        ))
+        .unwrap();
+        assert_eq!(transfer.token, Address::from_low_u64_be(1));
+        assert_eq!(transfer.from, Address::from_low_u64_be(2));
+        assert_eq!(transfer.to, Address::from_low_u64_be(3));
+        assert_eq!(transfer.transferred, Transferred::Amount(U256::from(500)));
+        assert_eq!(transfer.amount(), U256::from(500));
+    }
+
+    #[test]
+    fn test_decode_call() {
+        let (token, sender) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let call = |signature: &str, params: Vec<Token>| {
+            [&keccak256(signature)[..4], &abi::encode(&params)].concat()
+        };
+        let transfer = Transfer::decode_call(
+            token,
+            sender,
+            &call(
+                TRANSFER_CALL,
+                vec![
+                    Token::Address(Address::from_low_u64_be(3)),
+                    Token::Uint(7.into()),
+                ],
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                token,
+                from: sender,
+                to: Address::from_low_u64_be(3),
+                transferred: Transferred::Amount(7.into()),
+            }
+        );
+
+        let transfer_from = call(
+            TRANSFER_FROM_CALL,
+            vec![
+                Token::Address(Address::from_low_u64_be(4)),
+                Token::Address(Address::from_low_u64_be(3)),
+                Token::Uint(9.into()),
+            ],
+        );
+        let transfer = Transfer::decode_call(token, sender, &transfer_from).unwrap();
+        assert_eq!(transfer.from, Address::from_low_u64_be(4));
+        assert_eq!(transfer.amount(), 9.into());
+
+        assert!(Transfer::decode_call(token, sender, &transfer_from[..40]).is_none());
+        assert!(
+            Transfer::decode_call(token, sender, &call("approve(address,uint256)", vec![]))
+                .is_none()
+        );
+        assert!(Transfer::decode_call(token, sender, &[]).is_none());
+    }
+
+    #[test]
+    fn test_decode_unindexed() {
+        let expected = Transfer {
+            token: Address::from_low_u64_be(1),
+            from: Address::from_low_u64_be(2),
+            to: Address::from_low_u64_be(3),
+            transferred: Transferred::Amount(U256::from(500)),
+        };
+        let unindexed = log(
+            TRANSFER,
+            &[],
+            vec![
+                Token::Address(Address::from_low_u64_be(2)),
+                Token::Address(Address::from_low_u64_be(3)),
+                Token::Uint(U256::from(500)),
+            ],
+        );
+        assert_eq!(Transfer::decode(&unindexed), Ok(expected.clone()));
+        let from_indexed = log(
+            TRANSFER,
+            &[topic(2)],
+            vec![
+                Token::Address(Address::from_low_u64_be(3)),
+                Token::Uint(U256::from(500)),
+            ],
+        );
+        assert_eq!(Transfer::decode(&from_indexed), Ok(expected));
+    }
+
+    #[test]
+    fn test_decode_erc721() {
+        let token_id = H256::from_low_u64_be(42);
+        let transfer =
+            Transfer::decode(&log(TRANSFER, &[topic(2), topic(3), token_id], vec![])).unwrap();
+        assert_eq!(transfer.to, Address::from_low_u64_be(3));
+        assert_eq!(transfer.transferred, Transferred::Nft(U256::from(42)));
+        assert_eq!(transfer.amount(), U256::one());
+    }
+
+    #[test]
+    fn test_decode_erc1155() {
+        let single = Transfer::decode(&log(
+            TRANSFER_SINGLE,
+            &[topic(9), topic(2), topic(3)],
+            vec![Token::Uint(U256::from(7)), Token::Uint(U256::from(5))],
+        ))
+        .unwrap();
+        assert_eq!(
+            (single.from, single.to),
+            (Address::from_low_u64_be(2), Address::from_low_u64_be(3))
+        );
+        assert_eq!(
+            single.transferred,
+            Transferred::Ids(vec![(U256::from(7), U256::from(5))])
+        );
+
+        let uints = |values: &[u64]| {
+            Token::Array(
+                values
+                    .iter()
+                    .map(|value| Token::Uint(U256::from(*value)))
+                    .collect(),
+            )
+        };
+        let batch = Transfer::decode(&log(
+            TRANSFER_BATCH,
+            &[topic(9), topic(2), topic(3)],
+            vec![uints(&[1, 2]), uints(&[10, 20])],
+        ))
+        .unwrap();
+        assert_eq!(batch.amount(), U256::from(30));
+        assert_eq!(
+            Transfer::decode(&log(
+                TRANSFER_BATCH,
+                &[topic(9), topic(2), topic(3)],
+                vec![uints(&[1, 2]), uints(&[10])],
+            )),
+            Err(Reject::Batch)
+        );
+        assert_eq!(
+            Transfer::decode(&log(
+                TRANSFER_BATCH,
+                &[topic(9), topic(2)],
+                vec![uints(&[1]), uints(&[10])],
+            )),
+            Err(Reject::Topics)
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects() {
+        let amount = vec![Token::Uint(U256::one())];
+        assert_eq!(
+            Transfer::decode(&log(
+                "Approval(address,address,uint256)",
+                &[topic(2), topic(3)],
+                amount.clone(),
+            )),
+            Err(Reject::UnknownEvent)
+        );
+        assert_eq!(Transfer::decode(&Log::default()), Err(Reject::UnknownEvent));
+        // ERC721 without the token id in data, or an ERC20 missing its amount.
+        assert_eq!(
+            Transfer::decode(&log(TRANSFER, &[topic(2), topic(3)], vec![])),
+            Err(Reject::Data)
+        );
+        assert_eq!(
+            Transfer::decode(&log(
+                TRANSFER,
+                &[topic(2), topic(3), topic(4), topic(5)],
+                vec![]
+            )),
+            Err(Reject::Topics)
+        );
+        assert_eq!(
+            Transfer::decode(&log(TRANSFER, &[H256::repeat_byte(0xff), topic(3)], amount)),
+            Err(Reject::Address)
+        );
+    }
+
+    #[test]
+    fn test_rejected_logs() {
+        let rejected = RejectedLogs::default();
+        rejected.record(1, Reject::Data);
+        rejected.clone().record(1, Reject::Data);
+        rejected.record(5, Reject::Topics);
+        assert_eq!(
+            rejected.counts(),
+            vec![(1, Reject::Data, 2), (5, Reject::Topics, 1)]
+        );
+    }
+
+    fn any_signature() -> impl Strategy<Value = H256> {
+        prop_oneof![
+            Just(signature_topic(TRANSFER)),
+            Just(signature_topic(TRANSFER_SINGLE)),
+            Just(signature_topic(TRANSFER_BATCH)),
+            any::<[u8; 32]>().prop_map(H256::from),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_never_panics(
+            signature in any_signature(),
+            topics in prop::collection::vec(any::<[u8; 32]>().prop_map(H256::from), 0..5),
+            data in prop::collection::vec(any::<u8>(), 0..300),
+        ) {
+            let log = Log {
+                topics: [vec![signature], topics].concat(),
+                data: data.into(),
+                ..Default::default()
+            };
+            let _ = Transfer::decode(&log);
+        }
+
+        #[test]
+        fn test_decode_any_indexing(
+            from in any::<[u8; 20]>().prop_map(Address::from),
+            to in any::<[u8; 20]>().prop_map(Address::from),
+            amount in any::<[u8; 32]>().prop_map(|bytes| U256::from_big_endian(&bytes)),
+            indexed in 0..=2usize,
+        ) {
+            let params = [Token::Address(from), Token::Address(to), Token::Uint(amount)];
+            let topics: Vec<H256> = params[..indexed]
+                .iter()
+                .map(|param| H256::from_slice(&abi::encode(std::slice::from_ref(param))))
+                .collect();
+            let transfer = Transfer::decode(&log(TRANSFER, &topics, params[indexed..].to_vec()));
+            prop_assert_eq!(
+                transfer,
+                Ok(Transfer {
+                    token: Address::from_low_u64_be(1),
+                    from,
+                    to,
+                    transferred: Transferred::Amount(amount),
+                })
+            );
+        }
+    }
+}